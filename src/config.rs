@@ -0,0 +1,308 @@
+//! Default options read from a `--config` file and turned back into command line arguments,
+//! so clap parses and validates them exactly like flags typed by hand.
+
+use anyhow::{anyhow, bail, Result};
+use clap::{Arg, Command};
+
+/// One value from a config file, in command line form.
+#[derive(Debug, PartialEq)]
+pub enum ConfigValue {
+    Switch(bool),
+    Value(String),
+}
+
+/// Parses the flat subset of TOML a config needs: `key = value` lines holding strings,
+/// numbers, booleans or single-line arrays of those, plus `#` comments. Tables (including
+/// inline ones) and arrays split over several lines aren't supported and are rejected,
+/// since every option lives at the top level and fits on one line.
+pub fn parse_config(contents: &str) -> Result<Vec<(String, Vec<ConfigValue>)>> {
+    let mut entries: Vec<(String, Vec<ConfigValue>)> = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with('[') {
+            bail!("line {}: tables are not supported", i + 1);
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            bail!("line {}: expected `key = value`", i + 1);
+        };
+        let key = key.trim();
+        if key.is_empty() {
+            bail!("line {}: missing key", i + 1);
+        }
+        if entries.iter().any(|(k, _)| k == key) {
+            bail!("line {}: duplicate key '{}'", i + 1, key);
+        }
+        let values = parse_values(value).ok_or_else(|| {
+            let value = value.trim_start();
+            if value.starts_with('{') {
+                anyhow!("line {}: inline tables are not supported", i + 1)
+            } else if value.starts_with('[') && !value.contains(']') {
+                anyhow!("line {}: arrays must fit on one line", i + 1)
+            } else {
+                anyhow!("line {}: invalid value for '{}'", i + 1, key)
+            }
+        })?;
+        entries.push((key.to_string(), values));
+    }
+    Ok(entries)
+}
+
+// a single value or an array of them, followed by nothing but an optional comment
+fn parse_values(text: &str) -> Option<Vec<ConfigValue>> {
+    let mut values = Vec::new();
+    let rest = match text.trim_start().strip_prefix('[') {
+        Some(mut rest) => loop {
+            rest = rest.trim_start();
+            if let Some(rest) = rest.strip_prefix(']') {
+                break rest;
+            }
+            let (value, after) = parse_value(rest)?;
+            values.push(value);
+            let after = after.trim_start();
+            rest = match after.strip_prefix(',') {
+                Some(after) => after,
+                None if after.starts_with(']') => after,
+                None => return None,
+            };
+        },
+        None => {
+            let (value, rest) = parse_value(text)?;
+            values.push(value);
+            rest
+        }
+    };
+    let rest = rest.trim();
+    (rest.is_empty() || rest.starts_with('#')).then_some(values)
+}
+
+// one value from the start of `text`, and whatever follows it
+fn parse_value(text: &str) -> Option<(ConfigValue, &str)> {
+    let text = text.trim_start();
+    if let Some(rest) = text.strip_prefix('"') {
+        let mut value = String::new();
+        let mut chars = rest.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => return Some((ConfigValue::Value(value), &rest[i + 1..])),
+                '\\' => value.push(match chars.next()?.1 {
+                    'n' => '\n',
+                    't' => '\t',
+                    c => c,
+                }),
+                c => value.push(c),
+            }
+        }
+        return None;
+    }
+    if let Some(rest) = text.strip_prefix('\'') {
+        // literal string: no escapes
+        let end = rest.find('\'')?;
+        return Some((
+            ConfigValue::Value(rest[..end].to_string()),
+            &rest[end + 1..],
+        ));
+    }
+
+    let end = text.find([',', ']', '#']).unwrap_or(text.len());
+    let (token, rest) = text.split_at(end);
+    let value = match token.trim() {
+        "true" => ConfigValue::Switch(true),
+        "false" => ConfigValue::Switch(false),
+        // TOML allows `_` between digits (1_000)
+        number if !number.is_empty() && number.replace('_', "").parse::<f64>().is_ok() => {
+            ConfigValue::Value(number.replace('_', ""))
+        }
+        _ => return None,
+    };
+    Some((value, rest))
+}
+
+/// Turns config entries into `--key=value` arguments for `command`. `true` becomes a bare
+/// flag, `false` leaves it off and arrays repeat the flag. Keys may use `-` or `_`.
+/// Options already given in `cli` are skipped entirely, so a flag on the command line
+/// replaces the config's value(s) rather than adding to them. So are options that conflict
+/// with one given in `cli` (e.g. `format` when `--template` is used).
+pub fn config_args(
+    config: &[(String, Vec<ConfigValue>)],
+    command: &Command,
+    cli: &[String],
+) -> Result<Vec<String>> {
+    let find = |long: &str| command.get_arguments().find(|a| a.get_long() == Some(long));
+    let given: Vec<&Arg> = cli_flags(command, cli)
+        .into_iter()
+        .filter_map(&find)
+        .collect();
+
+    let mut args = Vec::new();
+    for (key, values) in config {
+        let flag = key.replace('_', "-");
+        let arg = match find(&flag) {
+            Some(arg) if flag != "config" => arg,
+            _ => bail!("Unknown option '{}'", key),
+        };
+        if given.iter().any(|cli_arg| replaces(command, cli_arg, arg)) {
+            continue;
+        }
+        for value in values {
+            match value {
+                ConfigValue::Switch(true) => args.push(format!("--{}", flag)),
+                ConfigValue::Switch(false) => {}
+                ConfigValue::Value(value) => args.push(format!("--{}={}", flag, value)),
+            }
+        }
+    }
+    Ok(args)
+}
+
+// whether `cli_arg`, given on the command line, replaces the config's `arg`: it's the same
+// option, or clap would reject the two together
+fn replaces(command: &Command, cli_arg: &Arg, arg: &Arg) -> bool {
+    let conflicts = |a: &Arg, b: &Arg| {
+        command
+            .get_arg_conflicts_with(a)
+            .iter()
+            .any(|c| c.get_id() == b.get_id())
+    };
+    cli_arg.get_id() == arg.get_id() || conflicts(cli_arg, arg) || conflicts(arg, cli_arg)
+}
+
+// long names of the options used in `cli`, including ones given by their short flag
+fn cli_flags<'a>(command: &'a Command, cli: &'a [String]) -> Vec<&'a str> {
+    let mut flags = Vec::new();
+    for arg in cli.iter().take_while(|arg| *arg != "--") {
+        if let Some(long) = arg.strip_prefix("--") {
+            flags.push(long.split('=').next().unwrap_or(long));
+        } else if let Some(shorts) = arg.strip_prefix('-') {
+            // `-vq` sets both; anything that isn't a known short flag (like `-12`) is a value
+            flags.extend(shorts.chars().filter_map(|short| {
+                command
+                    .get_arguments()
+                    .find(|a| a.get_short() == Some(short))
+                    .and_then(|a| a.get_long())
+            }));
+        }
+    }
+    flags
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Args;
+    use clap::{CommandFactory, Parser};
+
+    const CONFIG: &str = r#"
+# shared settings for every song
+override_midi_channel = 5
+transpose = -12
+format = "json"
+note-names = true
+dedupe_cc = false
+track = [1, 2]
+"#;
+
+    fn parse_with_config(cli: &[&str]) -> Args {
+        let cli: Vec<String> = cli.iter().map(|arg| arg.to_string()).collect();
+        let config = parse_config(CONFIG).unwrap();
+        let mut args = vec!["midi2stagetraxx".to_string()];
+        args.extend(config_args(&config, &Args::command(), &cli).unwrap());
+        args.extend(cli);
+        Args::try_parse_from(args).unwrap()
+    }
+
+    #[test]
+    fn parses_values_arrays_and_comments() {
+        let config = parse_config("a = 'x y' # note\nb = [1, -2.5]\nc = true\n").unwrap();
+        assert_eq!(
+            config,
+            vec![
+                ("a".to_string(), vec![ConfigValue::Value("x y".to_string())]),
+                (
+                    "b".to_string(),
+                    vec![
+                        ConfigValue::Value("1".to_string()),
+                        ConfigValue::Value("-2.5".to_string())
+                    ]
+                ),
+                ("c".to_string(), vec![ConfigValue::Switch(true)]),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        assert!(parse_config("[section]").is_err());
+        assert!(parse_config("transpose").is_err());
+        assert!(parse_config("format = \"json").is_err());
+        assert!(parse_config("a = 1\na = 2").is_err());
+        assert!(parse_config("a = { b = 1 }").is_err());
+        assert!(parse_config("track = [1,\n  2]").is_err());
+    }
+
+    #[test]
+    fn turns_entries_into_flags() {
+        let config = parse_config(CONFIG).unwrap();
+        let args = config_args(&config, &Args::command(), &[]).unwrap();
+        assert_eq!(
+            args,
+            [
+                "--override-midi-channel=5",
+                "--transpose=-12",
+                "--format=json",
+                "--note-names",
+                "--track=1",
+                "--track=2",
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_keys_are_an_error() {
+        let config = parse_config("no_such_option = 1").unwrap();
+        assert!(config_args(&config, &Args::command(), &[]).is_err());
+        let config = parse_config("config = \"other.toml\"").unwrap();
+        assert!(config_args(&config, &Args::command(), &[]).is_err());
+    }
+
+    #[test]
+    fn config_values_apply_when_flag_absent() {
+        let args = parse_with_config(&["-m", "song.mid", "--transpose", "3"]);
+        assert_eq!(args.override_midi_channel, Some(5));
+        assert_eq!(args.transpose, 3);
+        assert!(args.note_names);
+        assert_eq!(args.tracks, [1, 2]);
+    }
+
+    #[test]
+    fn command_line_flags_replace_config_values() {
+        let config = parse_config(CONFIG).unwrap();
+        let cli = ["--track".to_string(), "3".to_string(), "-m".to_string()];
+        let args = config_args(&config, &Args::command(), &cli).unwrap();
+        assert!(!args.iter().any(|arg| arg.starts_with("--track")));
+        assert!(args.contains(&"--transpose=-12".to_string()));
+    }
+
+    #[test]
+    fn command_line_flags_replace_conflicting_config_values() {
+        let config =
+            parse_config("format = \"json\"\nverbose = true\noutput = \"out.txt\"\ntranspose = 2")
+                .unwrap();
+        for (flag, dropped) in [
+            ("--template", "--format"),
+            ("-q", "--verbose"),
+            ("--output-dir=out", "--output"),
+        ] {
+            let args = config_args(&config, &Args::command(), &[flag.to_string()]).unwrap();
+            assert!(
+                !args.iter().any(|arg| arg.starts_with(dropped)),
+                "{} should drop {}",
+                flag,
+                dropped
+            );
+            assert!(args.contains(&"--transpose=2".to_string()));
+        }
+    }
+}
@@ -0,0 +1,172 @@
+use midi_file::core::Message;
+use midi_file::file::{Division, Event, MetaEvent};
+use midi_file::MidiFile;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::midi_event::{self, MidiEvent};
+
+// a conductor track with a handful of notes (e.g. a count-in) is common and harmless
+const MIXED_CONDUCTOR_NOTE_THRESHOLD: usize = 32;
+
+pub struct MixedConductorTrack {
+    pub track: usize,
+    pub conductor_events: usize,
+    pub note_events: usize,
+}
+
+/// Finds tracks that carry tempo/time-signature data alongside a large number of notes.
+pub fn mixed_conductor_tracks(midi_file: &MidiFile) -> Vec<MixedConductorTrack> {
+    midi_file
+        .tracks()
+        .enumerate()
+        .filter_map(|(i, track)| {
+            let mut conductor_events = 0;
+            let mut note_events = 0;
+            for track_event in track.events() {
+                match track_event.event() {
+                    Event::Meta(MetaEvent::SetTempo(_))
+                    | Event::Meta(MetaEvent::TimeSignature(_)) => conductor_events += 1,
+                    Event::Midi(Message::NoteOn(_)) | Event::Midi(Message::NoteOff(_)) => {
+                        note_events += 1
+                    }
+                    _ => {}
+                }
+            }
+
+            if conductor_events > 0 && note_events > MIXED_CONDUCTOR_NOTE_THRESHOLD {
+                Some(MixedConductorTrack {
+                    track: i,
+                    conductor_events,
+                    note_events,
+                })
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+// below this resolution, timing is too coarse for tight lighting cues
+const LOW_PPQ_THRESHOLD: u16 = 96;
+
+/// Runs every diagnostic over the file and returns a human readable issue per problem found.
+pub fn preflight(midi_file: &MidiFile) -> Vec<String> {
+    let mut issues = Vec::new();
+
+    match midi_file.header().division() {
+        Division::QuarterNote(ppq) if ppq.get() < LOW_PPQ_THRESHOLD => issues.push(format!(
+            "Low resolution: {} pulses per quarter note (< {})",
+            ppq.get(),
+            LOW_PPQ_THRESHOLD
+        )),
+        Division::QuarterNote(_) => {}
+        Division::Smpte(smpte) => issues.push(format!(
+            "SMPTE division ({:?}): timing follows the frame clock and ignores tempo",
+            smpte
+        )),
+    }
+
+    let mut first_tempo_tick: Option<u32> = None;
+    let mut first_midi_tick: Option<u32> = None;
+    let mut held_notes: HashMap<(u8, u8), u32> = HashMap::new();
+    for track in midi_file.tracks() {
+        let mut ticks: u32 = 0;
+        for track_event in track.events() {
+            ticks += track_event.delta_time();
+            match track_event.event() {
+                Event::Meta(MetaEvent::SetTempo(_)) => {
+                    first_tempo_tick = Some(first_tempo_tick.map_or(ticks, |t| t.min(ticks)));
+                }
+                Event::Midi(msg) => {
+                    first_midi_tick = Some(first_midi_tick.map_or(ticks, |t| t.min(ticks)));
+                    match msg {
+                        Message::NoteOn(note) if note.velocity().get() > 0 => {
+                            *held_notes
+                                .entry((note.channel().get(), note.note_number().get()))
+                                .or_insert(0) += 1;
+                        }
+                        Message::NoteOn(note) | Message::NoteOff(note) => {
+                            if let Some(held) = held_notes
+                                .get_mut(&(note.channel().get(), note.note_number().get()))
+                            {
+                                *held = held.saturating_sub(1);
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    match (first_tempo_tick, first_midi_tick) {
+        (None, Some(_)) => issues.push("No tempo set: timing will assume 120 BPM".to_string()),
+        (Some(tempo), Some(midi)) if tempo > midi => issues.push(format!(
+            "First tempo change (tick {}) comes after the first MIDI event (tick {})",
+            tempo, midi
+        )),
+        _ => {}
+    }
+
+    for mixed in mixed_conductor_tracks(midi_file) {
+        issues.push(format!(
+            "Track {} mixes {} tempo/time signature events with {} note events",
+            mixed.track, mixed.conductor_events, mixed.note_events
+        ));
+    }
+
+    let stuck: u32 = held_notes.values().sum();
+    if stuck > 0 {
+        issues.push(format!("{} note(s) are never turned off", stuck));
+    }
+
+    issues
+}
+
+/// Summary of an extracted event list, for a sanity check before writing it out.
+pub struct EventStats {
+    pub total: usize,
+    pub per_type: BTreeMap<&'static str, usize>,
+    pub channels: usize,
+    pub tempo_changes: usize,
+    pub final_timestamp: Option<f64>,
+    pub duration: Option<f64>, // length of the file, including trailing silence
+}
+
+pub fn event_stats(
+    events: &[MidiEvent],
+    tempo_changes: usize,
+    duration: Option<f64>,
+) -> EventStats {
+    let mut per_type = BTreeMap::new();
+    for event in events {
+        *per_type.entry(message_type(&event.message)).or_insert(0) += 1;
+    }
+    EventStats {
+        total: events.len(),
+        per_type,
+        channels: events
+            .iter()
+            .map(|e| e.channel)
+            .collect::<HashSet<_>>()
+            .len(),
+        tempo_changes,
+        final_timestamp: events.iter().map(|e| e.timestamp).reduce(f64::max),
+        duration,
+    }
+}
+
+fn message_type(message: &midi_event::Message) -> &'static str {
+    match message {
+        midi_event::Message::NoteOn(..) => "note on",
+        midi_event::Message::NoteOff(..) => "note off",
+        midi_event::Message::ControlChange(..) => "control change",
+        midi_event::Message::ControlChange14(..) => "14-bit control change",
+        midi_event::Message::ProgramChange(..) => "program change",
+        midi_event::Message::PitchBend(..) => "pitch bend",
+        midi_event::Message::ChannelPressure(..) => "channel pressure",
+        midi_event::Message::PolyPressure(..) => "poly pressure",
+        midi_event::Message::Note(..) => "note",
+    }
+}
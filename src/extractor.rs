@@ -1,91 +1,609 @@
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
 
 use crate::midi_event;
-use midi_file::core::{ControlChangeValue, NoteMessage};
+use crate::tempo_map::TempoMap;
+use crate::timing::{TimeDivision, Timeline, DEFAULT_BPM, MICROS_PER_SEC};
+use crate::{debug, info, warn};
+use midi_file::core::{
+    ChannelPressureMessage, ControlChangeValue, NoteMessage, PitchBendMessage, ProgramChangeValue,
+};
 use midi_file::file::SmpteOffsetValue;
-use midi_file::file::TrackEvent;
-use midi_file::file::{Division, MetaEvent};
+use midi_file::file::{Division, FrameRate, MetaEvent, TimeSignatureValue};
+use midi_file::file::{Format, Track, TrackEvent};
 use midi_file::{core::Message, file::Event, MidiFile};
+use std::collections::{BTreeMap, HashMap};
 use std::mem;
+use std::ops::RangeInclusive;
 
 pub struct Extractor {
     midi_file: MidiFile,
     override_midi_channel: Option<u8>,
-    pulses_per_qn: u16,
+    override_cc_channel: Option<u8>,
+    override_scope: OverrideScope,
+    channel_per_track: bool,
+    only_channel: Option<u8>,
+    off_velocity_threshold: u8,
+    require_tempo: bool,
+    velocity_step: Option<u8>,
+    transpose: i8,
+    note_range: RangeInclusive<u8>,
+    include_aftertouch: bool,
+    flush_held_notes: bool,
+    apply_smpte_offset: bool,
+    // seconds added to every timestamp (the file's SMPTE offset, when applied)
+    start_offset: f64,
+    // Type 2 files: seconds between one track's sequence ending and the next one starting
+    track_gap: f64,
+    sequence_start: f64,
+    velocity_curve: VelocityCurve,
+    channel_base: u8,
+    // grid step in ticks
+    quantize: Option<u32>,
+    // (0-based channel, note) -> snapped tick of the NoteOn still sounding
+    quantized_note_ons: HashMap<(u8, u8), u32>,
+    assume_first_tempo: bool,
+    note_off_first: bool,
+    // 0-based source channel -> 1-based output channel
+    channel_remap: HashMap<u8, u8>,
+    // (min, max) output range for CC values, min > max inverts
+    cc_scale: Option<(u8, u8)>,
+    // track indices to extract, empty for all tracks
+    tracks: Vec<usize>,
+    // (absolute tick, micros per qn) replacing the file's own SetTempo events
+    tempo_overrides: Option<Vec<(u32, u32)>>,
+    force_bpm: Option<f64>,
+    timeline: Timeline,
+    tempo_change_count: usize,
+    meter_segments: Vec<MeterSegment>,
+    first_tempo_tick: Option<u32>,
+    division: TimeDivision,
+    current_track: usize,
+    // tick the current MIDI event is placed at, after quantizing
+    event_tick: u32,
+    markers: Vec<midi_event::Marker>,
+    // (track, absolute tick) of each EndOfTrack seen during `run`
+    end_of_track_ticks: Vec<(usize, u32)>,
     ticks: u32,
-    last_tempo_change_ticks: u32,
-    elapsed_sec: f64,
     last_midi_event_ts: f64,
-    current_tempo_micros_per_qn: u32,
 }
 
-// microseconds per second
-const MICROS_PER_SEC: f64 = 1_000_000.0;
-const DEFAULT_BPM: f64 = 120.0;
+/// A stretch of the timeline in a single time signature, starting at `tick` on a bar line.
+#[derive(Clone, Copy, Debug)]
+struct MeterSegment {
+    tick: u32,
+    bar: u32, // 0-based index of the bar starting at `tick`
+    beats_per_bar: u32,
+    ticks_per_beat: u32,
+}
+
+/// Shape applied to NoteOn velocities.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VelocityCurve {
+    Linear,
+    /// Gamma 2: soft hits stay soft, the top of the range opens up
+    Exp,
+    /// Gamma 1/2: soft hits ramp up faster
+    Log,
+    /// `127 - v`
+    Invert,
+}
+
+/// Which messages `override_midi_channel` applies to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OverrideScope {
+    /// Notes only
+    Notes,
+    /// Control changes only
+    ControlChanges,
+    /// Every message type
+    All,
+}
+
+#[derive(Clone, Copy)]
+enum MessageKind {
+    Note,
+    ControlChange,
+    ProgramChange,
+    PitchBend,
+    Aftertouch,
+}
 
 impl Extractor {
+    /// `override_midi_channel` is 1-based (1..=16), like the channels in the output.
     pub fn new(midi_file: MidiFile, override_midi_channel: Option<u8>) -> Result<Self> {
-        // read division to get pulses per quarter note
+        if let Some(channel) = override_midi_channel.filter(|c| !(1..=16).contains(c)) {
+            bail!("Override MIDI channel {} is out of range 1..=16", channel);
+        }
+
+        // read division to get pulses per quarter note (or ticks per SMPTE frame)
         let div = midi_file.header().division();
 
-        let pulses_per_qn: u16 = match div {
+        let division = match div {
             Division::QuarterNote(qtr) => {
-                println!("Quarter Note Division: {}", qtr);
-                qtr.get()
+                debug!("Quarter Note Division: {}", qtr);
+                TimeDivision::PulsesPerQuarterNote(qtr.get())
             }
             Division::Smpte(smpte) => {
-                // don't think we need this for now, but we can add it later
-                println!("SMPTE Division: {:?}", smpte);
-                unimplemented!("SMPTE division")
+                // absolute clock: timing comes from the frame rate, tempo changes don't apply
+                debug!("SMPTE Division: {:?}", smpte);
+                let frames_per_sec = match smpte.frame_rate() {
+                    FrameRate::N24 => 24.0,
+                    FrameRate::N25 => 25.0,
+                    FrameRate::N29 => 29.97,
+                    FrameRate::N30 => 30.0,
+                };
+                TimeDivision::Smpte {
+                    frames_per_sec,
+                    ticks_per_frame: smpte.resolution(),
+                }
             }
         };
 
         Ok(Self {
             midi_file,
             override_midi_channel,
-            pulses_per_qn,
+            override_cc_channel: None,
+            override_scope: OverrideScope::All,
+            channel_per_track: false,
+            only_channel: None,
+            off_velocity_threshold: 0,
+            require_tempo: false,
+            velocity_step: None,
+            transpose: 0,
+            note_range: 0..=127,
+            include_aftertouch: false,
+            flush_held_notes: true,
+            apply_smpte_offset: false,
+            start_offset: 0.0,
+            track_gap: 0.0,
+            sequence_start: 0.0,
+            velocity_curve: VelocityCurve::Linear,
+            channel_base: 1,
+            quantize: None,
+            quantized_note_ons: HashMap::new(),
+            assume_first_tempo: false,
+            note_off_first: true,
+            channel_remap: HashMap::new(),
+            cc_scale: None,
+            tracks: Vec::new(),
+            tempo_overrides: None,
+            force_bpm: None,
+            timeline: Timeline::new(&[], division),
+            tempo_change_count: 0,
+            meter_segments: Vec::new(),
+            first_tempo_tick: None,
+            division,
+            current_track: 0,
+            event_tick: 0,
+            markers: Vec::new(),
+            end_of_track_ticks: Vec::new(),
             ticks: 0,
-            last_tempo_change_ticks: 0,
-            elapsed_sec: 0.0,
             last_midi_event_ts: 0.0,
-            current_tempo_micros_per_qn: (MICROS_PER_SEC / (DEFAULT_BPM / 60.0)) as u32,
         })
     }
 
+    /// Forces control changes onto a single channel, leaving notes alone.
+    /// Takes precedence over `override_midi_channel` for CC events.
+    pub fn with_override_cc_channel(mut self, channel: Option<u8>) -> Self {
+        self.override_cc_channel = channel;
+        self
+    }
+
+    /// Limits `override_midi_channel` to notes or control changes (default: everything).
+    /// `with_override_cc_channel` is not affected.
+    pub fn with_override_scope(mut self, scope: OverrideScope) -> Self {
+        self.override_scope = scope;
+        self
+    }
+
+    /// Emits each source track on its own channel (track index + 1, clamped to 16),
+    /// ignoring the channel in the file.
+    pub fn with_channel_per_track(mut self, channel_per_track: bool) -> Self {
+        self.channel_per_track = channel_per_track;
+        self
+    }
+
+    /// Only extract messages on this 1-based channel, as found in the file
+    /// (before any channel override is applied).
+    pub fn with_only_channel(mut self, channel: Option<u8>) -> Self {
+        self.only_channel = channel;
+        self
+    }
+
+    /// NoteOns with a velocity at or below `threshold` are treated as NoteOffs.
+    /// The default of 0 follows the MIDI convention of velocity 0 meaning off.
+    pub fn with_off_velocity_threshold(mut self, threshold: u8) -> Self {
+        self.off_velocity_threshold = threshold;
+        self
+    }
+
+    /// When the first tempo change comes after the first MIDI event, apply it from tick 0
+    /// instead of timing the earlier events at the default tempo.
+    pub fn with_assume_first_tempo(mut self, assume_first_tempo: bool) -> Self {
+        self.assume_first_tempo = assume_first_tempo;
+        self
+    }
+
+    /// Fail instead of assuming the default tempo when no `SetTempo` precedes the first MIDI event.
+    pub fn with_require_tempo(mut self, require_tempo: bool) -> Self {
+        self.require_tempo = require_tempo;
+        self
+    }
+
+    /// Reshapes NoteOn velocities, before any velocity step is applied.
+    pub fn with_velocity_curve(mut self, curve: VelocityCurve) -> Self {
+        self.velocity_curve = curve;
+        self
+    }
+
+    /// Rounds NoteOn velocities to the nearest multiple of `step`.
+    pub fn with_velocity_step(mut self, step: Option<u8>) -> Self {
+        self.velocity_step = step.filter(|s| *s > 0);
+        self
+    }
+
+    /// Shifts NoteOn/NoteOff note numbers by this many semitones, clamped to 0..=127.
+    pub fn with_transpose(mut self, semitones: i8) -> Self {
+        self.transpose = semitones;
+        self
+    }
+
+    /// At identical timestamps, put NoteOffs before NoteOns (the default) so the old scene
+    /// turns off before the new one turns on, or after them when `false`.
+    pub fn with_note_off_first(mut self, note_off_first: bool) -> Self {
+        self.note_off_first = note_off_first;
+        self
+    }
+
+    /// Snaps event times to the nearest `numerator/denominator` of a whole note (e.g. 1/16),
+    /// following the tempo map. Ignored for SMPTE division files, which have no beats.
+    pub fn with_quantize(mut self, grid: Option<(u32, u32)>) -> Self {
+        self.quantize = match (grid, self.division) {
+            (Some(_), TimeDivision::Smpte { .. }) => {
+                warn!("ignoring quantize, SMPTE division timing has no beat grid");
+                None
+            }
+            (Some((numerator, denominator)), TimeDivision::PulsesPerQuarterNote(ppq))
+                if numerator > 0 && denominator > 0 =>
+            {
+                // grid is a fraction of a whole note, which is four quarter notes
+                let grid = (ppq as u32)
+                    .checked_mul(4)
+                    .and_then(|ticks| ticks.checked_mul(numerator))
+                    .map(|ticks| (ticks / denominator).max(1));
+                if grid.is_none() {
+                    warn!(
+                        "ignoring quantize {}/{}, the grid is too long",
+                        numerator, denominator
+                    );
+                }
+                grid
+            }
+            _ => None,
+        };
+        self
+    }
+
+    /// Number added to the file's 0-based channels: 1 (the default) for 1-based consoles
+    /// like StageTraxx, 0 to keep channels as stored in the file.
+    pub fn with_channel_base(mut self, channel_base: u8) -> Self {
+        self.channel_base = channel_base.min(1);
+        self
+    }
+
+    /// Start the timeline at the file's SMPTE offset meta event instead of 0, for shows
+    /// synced to video timecode.
+    pub fn with_apply_smpte_offset(mut self, apply_smpte_offset: bool) -> Self {
+        self.apply_smpte_offset = apply_smpte_offset;
+        self
+    }
+
+    /// Add a NoteOff at the end for notes still held when the file ends (on by default).
+    pub fn with_flush_held_notes(mut self, flush_held_notes: bool) -> Self {
+        self.flush_held_notes = flush_held_notes;
+        self
+    }
+
+    /// Extract channel and polyphonic aftertouch instead of skipping them.
+    pub fn with_include_aftertouch(mut self, include_aftertouch: bool) -> Self {
+        self.include_aftertouch = include_aftertouch;
+        self
+    }
+
+    /// Sends messages from a 0-based source channel to a 1-based output channel.
+    /// Unlisted channels pass through; an override channel still takes precedence.
+    pub fn with_channel_remap(mut self, remap: HashMap<u8, u8>) -> Self {
+        self.channel_remap = remap;
+        self
+    }
+
+    /// Only extract notes whose (transposed) note number is within `min..=max`.
+    pub fn with_note_range(mut self, min: u8, max: u8) -> Self {
+        self.note_range = min..=max;
+        self
+    }
+
+    /// Linearly maps CC values from 0..=127 onto `min..=max`. `min > max` inverts the range.
+    pub fn with_cc_scale(mut self, range: Option<(u8, u8)>) -> Self {
+        self.cc_scale = range;
+        self
+    }
+
+    /// Silence, in milliseconds, between the sequences of a Type 2 file. Ignored for Type 0
+    /// and 1 files, whose tracks play at the same time.
+    pub fn with_track_gap_ms(mut self, gap_ms: u32) -> Self {
+        self.track_gap = gap_ms as f64 / 1000.0;
+        self
+    }
+
+    /// Only extract MIDI events from these track indices (all tracks when empty).
+    /// Tempo changes, markers and track ends are still read from every track (Type 2 files:
+    /// only the selected sequences are played at all).
+    pub fn with_tracks(mut self, tracks: Vec<usize>) -> Self {
+        self.tracks = tracks;
+        self
+    }
+
+    /// Replaces the tempo events embedded in the file with a user supplied tempo map. Fails
+    /// when the map doesn't fit the file's tick range.
+    pub fn with_tempo_map(mut self, tempo_map: Option<&TempoMap>) -> Result<Self> {
+        self.tempo_overrides = match (tempo_map, self.division) {
+            (Some(m), TimeDivision::PulsesPerQuarterNote(ppq)) => Some(m.tempo_changes(ppq)?),
+            (Some(_), TimeDivision::Smpte { .. }) => {
+                warn!("ignoring tempo map, SMPTE division timing does not use tempo");
+                None
+            }
+            (None, _) => None,
+        };
+        Ok(self)
+    }
+
+    /// Times every event at this fixed tempo, ignoring the file's `SetTempo` events and any
+    /// tempo map. Ignored for SMPTE division files, which don't use tempo.
+    pub fn with_force_bpm(mut self, bpm: Option<f64>) -> Self {
+        self.force_bpm = match (bpm, self.division) {
+            (Some(_), TimeDivision::Smpte { .. }) => {
+                warn!("ignoring forced BPM, SMPTE division timing does not use tempo");
+                None
+            }
+            (bpm, _) => bpm,
+        };
+        self
+    }
+
+    /// Number of tempo changes used for timing during `run` (from the file or the tempo map).
+    pub fn tempo_change_count(&self) -> usize {
+        self.tempo_change_count
+    }
+
+    /// Length of the extracted tracks, from their latest EndOfTrack (so trailing silence
+    /// counts), converted through the tempo map. None before `run` or without EndOfTrack events.
+    pub fn duration(&self) -> Option<f64> {
+        self.end_of_track_ticks
+            .iter()
+            .map(|(_, tick)| *tick)
+            .max()
+            .map(|tick| self.seconds_at(tick))
+    }
+
+    /// Marker meta events seen during `run`, in file order.
+    pub fn markers(&self) -> &[midi_event::Marker] {
+        &self.markers
+    }
+
     pub fn run(&mut self) -> Result<Vec<midi_event::MidiEvent>> {
-        let tracks = self.midi_file.tracks();
-        let track_events: Vec<TrackEvent> =
-            tracks.flat_map(|t| t.events().map(|e| e.clone())).collect();
+        let track_count = self.midi_file.tracks().count();
+        info!(
+            "Tracks: {} (indices 0..{})",
+            track_count,
+            track_count.saturating_sub(1)
+        );
+        if let Some(bad) = self.tracks.iter().find(|t| **t >= track_count) {
+            bail!(
+                "Track {} does not exist (file has {} tracks)",
+                bad,
+                track_count
+            );
+        }
+
+        if matches!(self.midi_file.header().format(), Format::Sequential) {
+            return self.run_sequential();
+        }
+
+        // tracks run in parallel, so convert each track's delta times to absolute ticks
+        // and merge them into a single timeline before doing any tempo math
+        let mut track_events: Vec<(u32, usize, TrackEvent)> = Vec::new();
+        for (i, track) in self.midi_file.tracks().enumerate() {
+            track_events.extend(absolute_ticks(i, track));
+        }
+        // stable, so events at the same tick stay in track order, then file order
+        track_events.sort_by_key(|(ticks, _, _)| *ticks);
+        self.run_timeline(track_events)
+    }
+
+    /// Type 2 files hold independent sequences, one per track, each with its own ticks and
+    /// tempo. They are timed separately and played back to back, `track_gap` seconds apart.
+    fn run_sequential(&mut self) -> Result<Vec<midi_event::MidiEvent>> {
+        debug!("Type 2 file: timing each track as its own sequence");
+        let sequences: Vec<Vec<(u32, usize, TrackEvent)>> = self
+            .midi_file
+            .tracks()
+            .enumerate()
+            .filter(|(i, _)| self.tracks.is_empty() || self.tracks.contains(i))
+            .map(|(i, track)| absolute_ticks(i, track))
+            .collect();
+
+        let mut results = Vec::new();
+        for track_events in sequences {
+            self.end_of_track_ticks.clear();
+            self.quantized_note_ons.clear();
+            let events = self.run_timeline(track_events)?;
+            // leave this sequence's SMPTE offset out, or every later sequence would add it
+            // again on top of its own
+            let end = self
+                .duration()
+                .or(events.last().map(|e| e.timestamp))
+                .map_or(self.sequence_start, |end| end - self.start_offset);
+            results.extend(events);
+            self.sequence_start = end + self.track_gap;
+        }
+        Ok(results)
+    }
+
+    /// Times and converts events already merged onto one tick timeline, sorted by tick.
+    fn run_timeline(
+        &mut self,
+        track_events: Vec<(u32, usize, TrackEvent)>,
+    ) -> Result<Vec<midi_event::MidiEvent>> {
+        // first pass: collect every tempo change into a map, so a note's timestamp doesn't
+        // depend on whether its tempo change happened to be processed before it
+        let mut tempo_changes: Vec<(u32, u32)> = match (self.force_bpm, &self.tempo_overrides) {
+            (Some(bpm), _) => vec![(0, (MICROS_PER_SEC * 60.0 / bpm).round() as u32)],
+            (None, Some(overrides)) => overrides.clone(),
+            (None, None) => track_events
+                .iter()
+                .filter_map(|(ticks, _, e)| match e.event() {
+                    Event::Meta(MetaEvent::SetTempo(tempo)) => Some((*ticks, tempo.get())),
+                    _ => None,
+                })
+                .collect(),
+        };
+        let first_midi_tick = track_events
+            .iter()
+            .find(|(_, _, e)| matches!(e.event(), Event::Midi(_)))
+            .map(|(ticks, _, _)| *ticks);
+        if let (Some(first_tempo), Some(first_midi)) = (tempo_changes.first_mut(), first_midi_tick)
+        {
+            if first_tempo.0 > first_midi {
+                if self.assume_first_tempo {
+                    warn!(
+                        "first tempo change is at tick {}, after the first MIDI event at tick {}; applying it from tick 0",
+                        first_tempo.0, first_midi
+                    );
+                    first_tempo.0 = 0;
+                } else {
+                    warn!(
+                        "first tempo change is at tick {}, after the first MIDI event at tick {}; earlier events are timed at {} BPM (use --assume-first-tempo to apply the first tempo from the start)",
+                        first_tempo.0, first_midi, DEFAULT_BPM
+                    );
+                }
+            }
+        }
+        self.tempo_change_count += tempo_changes.len();
+        if self.apply_smpte_offset {
+            let smpte_offset = track_events.iter().find_map(|(_, _, e)| match e.event() {
+                Event::Meta(MetaEvent::SmpteOffset(offset)) => Some(*offset),
+                _ => None,
+            });
+            self.start_offset = match smpte_offset.map(|o| decode_smpte_offset(&o)) {
+                Some(Ok(timecode)) => timecode.seconds(),
+                Some(Err(err)) => {
+                    warn!("not applying malformed SMPTE offset: {}", err);
+                    0.0
+                }
+                None => {
+                    warn!("no SMPTE offset in file, starting at 0");
+                    0.0
+                }
+            };
+        }
+        self.first_tempo_tick = tempo_changes.first().map(|(tick, _)| *tick);
+        self.timeline = Timeline::new(&tempo_changes, self.division);
 
-        let mut results: Vec<midi_event::MidiEvent> = Vec::new();
-        for track_event in track_events {
-            if let Some(event) = self.process_event(&track_event) {
-                results.push(event);
+        if let TimeDivision::PulsesPerQuarterNote(ppq) = self.division {
+            let meter_changes: Vec<(u32, TimeSignatureValue)> = track_events
+                .iter()
+                .filter_map(|(ticks, _, e)| match e.event() {
+                    Event::Meta(MetaEvent::TimeSignature(sig)) => Some((*ticks, sig.clone())),
+                    _ => None,
+                })
+                .collect();
+            self.meter_segments = meter_segments(&meter_changes, ppq);
+        }
+
+        // (same-time rank, source tick, event)
+        let mut results: Vec<(u8, u32, midi_event::MidiEvent)> = Vec::new();
+        // (channel, note) -> timestamp of its latest NoteOn
+        let mut last_note_on: HashMap<(u8, u8), f64> = HashMap::new();
+        for (ticks, track, track_event) in track_events {
+            // meta events (markers, end of track) count from every track, so deselecting
+            // a track doesn't lose section markers or shorten the song
+            let deselected = !self.tracks.is_empty() && !self.tracks.contains(&track);
+            if deselected && matches!(track_event.event(), Event::Midi(_)) {
+                continue;
             }
+            self.current_track = track;
+            let Some(event) = self.process_event(ticks, &track_event)? else {
+                continue;
+            };
+            let rank = match event.message {
+                midi_event::Message::NoteOn(note, _) => {
+                    last_note_on.insert((event.channel, note), event.timestamp);
+                    same_time_rank(&event.message, self.note_off_first)
+                }
+                // a zero-length note: its off must stay after its own on, or the note would
+                // look held until the end of the file
+                midi_event::Message::NoteOff(note, _)
+                    if last_note_on.get(&(event.channel, note)) == Some(&event.timestamp) =>
+                {
+                    ZERO_LENGTH_NOTE_OFF_RANK
+                }
+                _ => same_time_rank(&event.message, self.note_off_first),
+            };
+            results.push((rank, ticks, event));
+        }
+
+        // StageTraxx plays cues top to bottom, so an out of order line misfires silently.
+        // Simultaneous note offs and ons are put in a fixed order so a scene change can't
+        // flicker; otherwise events that share a timestamp follow their source ticks.
+        results.sort_by(|(a_rank, a_tick, a), (b_rank, b_tick, b)| {
+            a.timestamp
+                .total_cmp(&b.timestamp)
+                .then(a_rank.cmp(b_rank))
+                .then(a_tick.cmp(b_tick))
+        });
+        let mut results: Vec<midi_event::MidiEvent> =
+            results.into_iter().map(|(_, _, event)| event).collect();
+        if self.flush_held_notes {
+            flush_held_notes(&mut results, self.duration());
         }
 
         Ok(results)
     }
 
-    fn process_event(&mut self, track_event: &TrackEvent) -> Option<midi_event::MidiEvent> {
+    fn process_event(
+        &mut self,
+        ticks: u32,
+        track_event: &TrackEvent,
+    ) -> Result<Option<midi_event::MidiEvent>> {
         let dt = track_event.delta_time();
         let event = track_event.event();
-        self.ticks += dt;
-        match event {
+        self.ticks = ticks;
+        let result = match event {
             Event::Midi(msg) => {
-                let ticks_since_last_tempo_change = self.ticks - self.last_tempo_change_ticks;
-                let timestamp = self.elapsed_sec
-                    + ticks_to_seconds(
-                        ticks_since_last_tempo_change,
-                        self.pulses_per_qn,
-                        self.current_tempo_micros_per_qn,
+                let tempo_set = matches!(self.first_tempo_tick, Some(t) if t <= self.ticks);
+                if self.require_tempo && !tempo_set {
+                    bail!(
+                        "No tempo set before the first MIDI event (tick {}); refusing to assume {} BPM",
+                        self.ticks,
+                        DEFAULT_BPM
                     );
+                }
+                let tick = self.quantized_tick(msg);
+                self.event_tick = tick;
+                let timestamp = self.seconds_at(tick);
                 self.last_midi_event_ts = timestamp;
-                self.handle_midi_msg(msg, timestamp, ticks_since_last_tempo_change)
+                self.handle_midi_msg(msg, timestamp, dt)
             }
 
             Event::Meta(MetaEvent::SetTempo(new_tempo)) => {
-                self.handle_tempo_change(new_tempo.get());
+                if self.force_bpm.is_some() {
+                    debug!("-- Ignoring file tempo change in favor of the forced BPM");
+                } else if self.tempo_overrides.is_some() {
+                    debug!("-- Ignoring file tempo change in favor of tempo map");
+                } else {
+                    self.handle_tempo_change(new_tempo.get());
+                }
                 None
             }
 
@@ -94,50 +612,170 @@ impl Extractor {
                 None
             }
 
+            Event::Meta(MetaEvent::Marker(text)) => {
+                let name = text.to_string();
+                debug!("-- MARKER: {}", name);
+                self.markers.push(midi_event::Marker {
+                    timestamp: self.current_timestamp(),
+                    name,
+                });
+                None
+            }
+
+            Event::Meta(MetaEvent::EndOfTrack) => {
+                debug!(
+                    "-- END OF TRACK {} at tick {}",
+                    self.current_track, self.ticks
+                );
+                self.end_of_track_ticks
+                    .push((self.current_track, self.ticks));
+                None
+            }
+
             Event::Meta(MetaEvent::TimeSignature(sig)) => {
-                eprintln!("-- TIME SIGNATURE: {:?}", sig);
+                debug!("-- TIME SIGNATURE: {:?}", sig);
                 None
             }
 
             _ => {
-                eprintln!("-- EVENT: {:?} {:?}", dt, event);
+                debug!("-- EVENT: {:?} {:?}", dt, event);
                 None
             }
+        };
+        Ok(result)
+    }
+
+    /// Seconds elapsed at the current tick.
+    fn current_timestamp(&self) -> f64 {
+        self.seconds_at(self.ticks)
+    }
+
+    /// Seconds at an absolute tick, starting from the SMPTE offset when it is applied (and,
+    /// in a Type 2 file, from where the current sequence starts).
+    fn seconds_at(&self, tick: u32) -> f64 {
+        self.sequence_start + self.start_offset + self.timeline.seconds_at(tick)
+    }
+
+    /// The current tick snapped to the quantize grid (unchanged without one). A NoteOff that
+    /// would land on or before its NoteOn's grid point is pushed one grid step past it, so
+    /// snapping can't reorder or swallow a note.
+    fn quantized_tick(&mut self, msg: &Message) -> u32 {
+        let Some(grid) = self.quantize else {
+            return self.ticks;
+        };
+        let snapped = self.ticks.saturating_add(grid / 2) / grid * grid;
+
+        let (note, on) = match msg {
+            Message::NoteOn(note) => (note, note.velocity().get() > self.off_velocity_threshold),
+            Message::NoteOff(note) => (note, false),
+            _ => return snapped,
+        };
+        let key = (note.channel().get(), note.note_number().get());
+        if on {
+            self.quantized_note_ons.insert(key, snapped);
+            snapped
+        } else {
+            match self.quantized_note_ons.remove(&key) {
+                Some(on_tick) if snapped <= on_tick => on_tick.saturating_add(grid),
+                _ => snapped,
+            }
         }
     }
 
+    /// Bar/beat position of an absolute tick, or None without a meter (SMPTE division).
+    fn bar_beat_at(&self, tick: u32) -> Option<midi_event::BarBeat> {
+        let i = self.meter_segments.partition_point(|s| s.tick <= tick);
+        let segment = self.meter_segments.get(i.checked_sub(1)?)?;
+        let ticks_per_bar = segment.ticks_per_beat * segment.beats_per_bar;
+        let offset = tick - segment.tick;
+        let into_bar = offset % ticks_per_bar;
+        Some(midi_event::BarBeat {
+            bar: segment.bar + offset / ticks_per_bar + 1,
+            beat: into_bar / segment.ticks_per_beat + 1,
+            tick: into_bar % segment.ticks_per_beat,
+        })
+    }
+
     fn handle_midi_msg(
         &self,
         msg: &Message,
         timestamp: f64,
         dt: u32,
     ) -> Option<midi_event::MidiEvent> {
+        if let Some(only) = self.only_channel {
+            if raw_channel(msg) + 1 != only {
+                return None;
+            }
+        }
+
         match msg {
-            Message::NoteOn(note) => Some(self.handle_note(note, timestamp, true)),
-            Message::NoteOff(note) => Some(self.handle_note(note, timestamp, false)),
+            Message::NoteOn(note) => {
+                // per the MIDI spec a NoteOn with velocity 0 is a NoteOff (common with
+                // running status); the threshold defaults to 0 and only widens that range
+                let on = note.velocity().get() > self.off_velocity_threshold;
+                self.handle_note(note, timestamp, on)
+            }
+            Message::NoteOff(note) => self.handle_note(note, timestamp, false),
             Message::Control(cc) => Some(self.handle_control_change(cc, timestamp)),
+            Message::ProgramChange(pc) => Some(self.handle_program_change(pc, timestamp)),
+            Message::PitchBend(pb) => Some(self.handle_pitch_bend(pb, timestamp)),
+            Message::ChannelPressure(cp) if self.include_aftertouch => {
+                Some(self.handle_channel_pressure(cp, timestamp))
+            }
+            Message::PolyPressure(note) if self.include_aftertouch => {
+                Some(self.handle_poly_pressure(note, timestamp))
+            }
             _ => {
-                eprintln!("Unhandled MIDI: {:?} {:?}", dt, msg);
+                debug!("Unhandled MIDI: {:?} {:?}", dt, msg);
                 None
             }
         }
     }
 
-    fn handle_note(&self, note: &NoteMessage, timestamp: f64, on: bool) -> midi_event::MidiEvent {
-        let velocity = if on { note.velocity().get() } else { 0 };
+    fn handle_note(
+        &self,
+        note: &NoteMessage,
+        timestamp: f64,
+        on: bool,
+    ) -> Option<midi_event::MidiEvent> {
+        let mut velocity = if on {
+            apply_velocity_curve(note.velocity().get(), self.velocity_curve)
+        } else {
+            0
+        };
+        if let Some(step) = self.velocity_step.filter(|_| on) {
+            velocity = round_to_step(velocity, step);
+        }
+        // the range applies to the output pitch, so it's checked after transposing
+        let note_number = self.transpose_note(note.note_number().get());
+        if !self.note_range.contains(&note_number) {
+            return None;
+        }
         let message = if on {
-            midi_event::Message::NoteOn(note.note_number().get(), velocity)
+            midi_event::Message::NoteOn(note_number, velocity)
         } else {
-            midi_event::Message::NoteOff(note.note_number().get(), velocity)
+            midi_event::Message::NoteOff(note_number, velocity)
         };
 
-        midi_event::MidiEvent {
+        Some(midi_event::MidiEvent {
             timestamp,
             message,
-            channel: self
-                .override_midi_channel
-                .unwrap_or(note.channel().get() + 1), // midi_file is 0-based
+            track: self.current_track,
+            bar_beat: self.bar_beat_at(self.event_tick),
+            channel: self.resolve_channel(note.channel().get(), MessageKind::Note),
+        })
+    }
+
+    fn transpose_note(&self, note: u8) -> u8 {
+        let shifted = note as i16 + self.transpose as i16;
+        let clamped = shifted.clamp(0, 127);
+        if clamped != shifted {
+            warn!(
+                "note {} transposed by {} is out of range, clamped to {} at tick {}",
+                note, self.transpose, clamped, self.ticks
+            );
         }
+        clamped as u8
     }
 
     fn handle_control_change(
@@ -147,41 +785,279 @@ impl Extractor {
     ) -> midi_event::MidiEvent {
         midi_event::MidiEvent {
             timestamp,
-            message: midi_event::Message::ControlChange(cc.control() as u8, cc.value().get() as u8),
-            channel: self.override_midi_channel.unwrap_or(cc.channel().get() + 1), // midi_file is 0-based
+            message: midi_event::Message::ControlChange(
+                cc.control() as u8,
+                self.scale_cc_value(cc.value().get() as u8),
+            ),
+            track: self.current_track,
+            bar_beat: self.bar_beat_at(self.event_tick),
+            channel: self.resolve_channel(cc.channel().get(), MessageKind::ControlChange),
         }
     }
 
-    fn handle_tempo_change(&mut self, new_tempo_micros_per_qn: u32) {
-        let bpm = MICROS_PER_SEC / new_tempo_micros_per_qn as f64 * 60.0;
-        eprintln!("-- Tempo change: {}", bpm);
+    fn scale_cc_value(&self, value: u8) -> u8 {
+        match self.cc_scale {
+            Some((min, max)) => scale_cc(value, min, max),
+            None => value,
+        }
+    }
+
+    fn handle_program_change(
+        &self,
+        pc: &ProgramChangeValue,
+        timestamp: f64,
+    ) -> midi_event::MidiEvent {
+        midi_event::MidiEvent {
+            timestamp,
+            message: midi_event::Message::ProgramChange(pc.program().get()),
+            track: self.current_track,
+            bar_beat: self.bar_beat_at(self.event_tick),
+            channel: self.resolve_channel(pc.channel().get(), MessageKind::ProgramChange),
+        }
+    }
+
+    fn handle_pitch_bend(&self, pb: &PitchBendMessage, timestamp: f64) -> midi_event::MidiEvent {
+        midi_event::MidiEvent {
+            timestamp,
+            message: midi_event::Message::PitchBend(centered_pitch_bend(pb.pitch_bend().get())),
+            track: self.current_track,
+            bar_beat: self.bar_beat_at(self.event_tick),
+            channel: self.resolve_channel(pb.channel().get(), MessageKind::PitchBend),
+        }
+    }
+
+    fn handle_channel_pressure(
+        &self,
+        cp: &ChannelPressureMessage,
+        timestamp: f64,
+    ) -> midi_event::MidiEvent {
+        midi_event::MidiEvent {
+            timestamp,
+            message: midi_event::Message::ChannelPressure(cp.pressure().get()),
+            track: self.current_track,
+            bar_beat: self.bar_beat_at(self.event_tick),
+            channel: self.resolve_channel(cp.channel().get(), MessageKind::Aftertouch),
+        }
+    }
 
-        let ticks_since_last_tempo_change = self.ticks - self.last_tempo_change_ticks;
-        self.last_tempo_change_ticks = self.ticks;
+    fn handle_poly_pressure(&self, note: &NoteMessage, timestamp: f64) -> midi_event::MidiEvent {
+        midi_event::MidiEvent {
+            timestamp,
+            // poly pressure reuses the note message layout, with the pressure in the velocity byte
+            message: midi_event::Message::PolyPressure(
+                note.note_number().get(),
+                note.velocity().get(),
+            ),
+            track: self.current_track,
+            bar_beat: self.bar_beat_at(self.event_tick),
+            channel: self.resolve_channel(note.channel().get(), MessageKind::Aftertouch),
+        }
+    }
 
-        self.elapsed_sec += ticks_to_seconds(
-            ticks_since_last_tempo_change,
-            self.pulses_per_qn,
-            self.current_tempo_micros_per_qn,
+    /// Every handler goes through here to turn a raw channel into the emitted one.
+    /// `raw_channel` is 0-based as read by midi_file; the result is offset by `channel_base`
+    /// (1 by default, for StageTraxx's 1-based channels). Explicit override and remap
+    /// channels are emitted exactly as given.
+    fn resolve_channel(&self, raw_channel: u8, kind: MessageKind) -> u8 {
+        if self.channel_per_track {
+            return (self.current_track.min(15) as u8) + self.channel_base;
+        }
+        let in_scope = matches!(
+            (self.override_scope, kind),
+            (OverrideScope::All, _)
+                | (OverrideScope::Notes, MessageKind::Note)
+                | (OverrideScope::ControlChanges, MessageKind::ControlChange)
         );
-        self.current_tempo_micros_per_qn = new_tempo_micros_per_qn;
+        let override_midi_channel = self.override_midi_channel.filter(|_| in_scope);
+        let override_channel = match kind {
+            MessageKind::Note
+            | MessageKind::ProgramChange
+            | MessageKind::PitchBend
+            | MessageKind::Aftertouch => override_midi_channel,
+            MessageKind::ControlChange => self.override_cc_channel.or(override_midi_channel),
+        };
+        override_channel
+            .or_else(|| self.channel_remap.get(&raw_channel).copied())
+            .unwrap_or(raw_channel + self.channel_base)
+    }
+
+    fn handle_tempo_change(&self, new_tempo_micros_per_qn: u32) {
+        // timing comes from the precomputed tempo map, this is just for visibility
+        let bpm = MICROS_PER_SEC / new_tempo_micros_per_qn as f64 * 60.0;
+        debug!("-- Tempo change: {} at tick {}", bpm, self.ticks);
     }
 
     fn handle_smpte_offset(&self, smpte_offset: &SmpteOffsetValue) {
-        eprintln!("-- SMPTE offset: {:?}", smpte_offset);
-        let (frame_rate, hr) = extract_frame_rate_hrs(smpte_offset);
-        eprintln!(
+        debug!("-- SMPTE offset: {:?}", smpte_offset);
+        // the offset comes from a file we don't control, so a bad one is skipped, not fatal
+        let timecode = match decode_smpte_offset(smpte_offset) {
+            Ok(timecode) => timecode,
+            Err(err) => {
+                warn!("ignoring malformed SMPTE offset: {}", err);
+                return;
+            }
+        };
+        debug!(
             "-- SMPTE OFFSET: ({:?}) frame: {}, hr: {}",
-            smpte_offset, frame_rate, hr
+            smpte_offset,
+            timecode.spec.frame_rate(),
+            timecode.hr
         );
+        debug!("-- SMPTE OFFSET: {:.3}s", timecode.seconds());
+    }
+}
+
+/// Appends a NoteOff at the end of the file (`duration`, or the final event when later) for
+/// every note still held, so a file that ends mid-note can't leave a light stuck on.
+/// Expects events sorted by timestamp.
+fn flush_held_notes(events: &mut Vec<midi_event::MidiEvent>, duration: Option<f64>) {
+    let Some(last) = events.last().map(|e| e.timestamp) else {
+        return;
+    };
+    let end = duration.map_or(last, |d| d.max(last));
+    // (channel, note) -> source tracks of the NoteOns still held, oldest first
+    let mut held: BTreeMap<(u8, u8), Vec<usize>> = BTreeMap::new();
+    for event in events.iter() {
+        match event.message {
+            midi_event::Message::NoteOn(note, _) => held
+                .entry((event.channel, note))
+                .or_default()
+                .push(event.track),
+            midi_event::Message::NoteOff(note, _) => {
+                if let Some(tracks) = held.get_mut(&(event.channel, note)) {
+                    tracks.pop();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    for ((channel, note), tracks) in held {
+        for track in tracks {
+            warn!(
+                "note {} on channel {} is still held at the end, adding a NoteOff at {:.3}s",
+                note, channel, end
+            );
+            events.push(midi_event::MidiEvent {
+                timestamp: end,
+                message: midi_event::Message::NoteOff(note, 0),
+                channel,
+                track,
+                bar_beat: None,
+            });
+        }
+    }
+}
+
+/// A track's events tagged with their absolute tick and the track index.
+fn absolute_ticks(track_index: usize, track: &Track) -> Vec<(u32, usize, TrackEvent)> {
+    let mut ticks: u32 = 0;
+    track
+        .events()
+        .map(|track_event| {
+            ticks += track_event.delta_time();
+            (ticks, track_index, track_event.clone())
+        })
+        .collect()
+}
+
+// after every other event at the same timestamp
+const ZERO_LENGTH_NOTE_OFF_RANK: u8 = 3;
+
+// tie-break for events at the same timestamp: the first kind of note message, then
+// everything else, then the other kind of note message
+fn same_time_rank(message: &midi_event::Message, note_off_first: bool) -> u8 {
+    match (message, note_off_first) {
+        (midi_event::Message::NoteOff(..), true) | (midi_event::Message::NoteOn(..), false) => 0,
+        (midi_event::Message::NoteOff(..), false) | (midi_event::Message::NoteOn(..), true) => 2,
+        _ => 1,
+    }
+}
+
+// 0-based channel of a channel voice message
+fn raw_channel(msg: &Message) -> u8 {
+    match msg {
+        Message::NoteOn(note) | Message::NoteOff(note) | Message::PolyPressure(note) => {
+            note.channel().get()
+        }
+        Message::Control(cc) => cc.channel().get(),
+        Message::ProgramChange(pc) => pc.channel().get(),
+        Message::PitchBend(pb) => pb.channel().get(),
+        Message::ChannelPressure(cp) => cp.channel().get(),
     }
 }
 
-fn ticks_to_seconds(ticks: u32, pulses_per_qn: u16, tempo: u32) -> f64 {
-    // MIDI tempo is in microseconds per quarter note
-    let tempo_in_secs = tempo as f64 / 1_000_000.0;
-    let beats = ticks as f64 / pulses_per_qn as f64;
-    beats * tempo_in_secs
+// pitch bend arrives as an unsigned 14-bit value (LSB + MSB << 7) with 8192 meaning no bend;
+// shift it so center is 0, giving -8192..=8191
+const PITCH_BEND_CENTER: i16 = 0x2000;
+
+fn centered_pitch_bend(raw: u16) -> i16 {
+    (raw & 0x3FFF) as i16 - PITCH_BEND_CENTER
+}
+
+fn scale_cc(value: u8, min: u8, max: u8) -> u8 {
+    let (value, min, max) = (value.min(127) as f64, min as f64, max as f64);
+    (min + value / 127.0 * (max - min)).round() as u8
+}
+
+fn apply_velocity_curve(velocity: u8, curve: VelocityCurve) -> u8 {
+    let x = velocity.min(127) as f64 / 127.0;
+    let shaped = match curve {
+        VelocityCurve::Linear => return velocity,
+        VelocityCurve::Exp => x.powi(2),
+        VelocityCurve::Log => x.sqrt(),
+        VelocityCurve::Invert => 1.0 - x,
+    };
+    // a NoteOn with velocity 0 is a NoteOff, so a curve must not silence a note
+    ((shaped * 127.0).round() as u8).clamp(1, 127)
+}
+
+// for NoteOn velocities: never rounds down to 0, which would turn the note into a NoteOff
+fn round_to_step(value: u8, step: u8) -> u8 {
+    let (value, step) = (value as u16, step as u16);
+    let rounded = (value + step / 2) / step * step;
+    rounded.clamp(step.min(127), 127) as u8
+}
+
+/// Builds the meter map from `(absolute tick, time signature)` changes sorted by tick,
+/// starting in 4/4. A change that lands mid-bar starts a new bar at that tick.
+fn meter_segments(changes: &[(u32, TimeSignatureValue)], ppq: u16) -> Vec<MeterSegment> {
+    let mut segments = vec![MeterSegment {
+        tick: 0,
+        bar: 0,
+        beats_per_bar: 4,
+        ticks_per_beat: ppq as u32,
+    }];
+    for (tick, sig) in changes {
+        // the denominator is stored as a power of two: 2 is a quarter note, 3 an eighth
+        let power = sig.denominator() as u32;
+        let beat_unit = 1u32.checked_shl(power).unwrap_or_else(|| {
+            warn!(
+                "time signature denominator 2^{} is out of range, counting quarter note beats",
+                power
+            );
+            4
+        });
+        let ticks_per_beat = (ppq as u32 * 4 / beat_unit).max(1);
+        let beats_per_bar = (sig.numerator() as u32).max(1);
+
+        let last_index = segments.len() - 1;
+        let last = segments[last_index];
+        if *tick == last.tick {
+            // a later change at the same tick wins
+            segments[last_index].beats_per_bar = beats_per_bar;
+            segments[last_index].ticks_per_beat = ticks_per_beat;
+            continue;
+        }
+        let ticks_per_bar = last.ticks_per_beat * last.beats_per_bar;
+        segments.push(MeterSegment {
+            tick: *tick,
+            bar: last.bar + (tick - last.tick).div_ceil(ticks_per_bar),
+            beats_per_bar,
+            ticks_per_beat,
+        });
+    }
+    segments
 }
 
 #[allow(dead_code)]
@@ -210,32 +1086,935 @@ impl SmpteFrameSpec {
             SmpteFrameSpec::F30 => 30.0,
         }
     }
+
+    // 29.97 is NTSC drop-frame: frame numbers 00 and 01 are skipped at the start of every
+    // minute except every tenth minute, so naive 30fps counting drifts ~3.6s per hour.
+    fn timecode_to_seconds(&self, hr: u8, mn: u8, se: u8, fr: u8, ff: u8) -> f64 {
+        let subframes = ff as f64 / 100.0;
+        match self {
+            SmpteFrameSpec::F2997 => {
+                let total_minutes = 60 * hr as u64 + mn as u64;
+                let dropped = 2 * (total_minutes - total_minutes / 10);
+                let frames =
+                    (3600 * hr as u64 + 60 * mn as u64 + se as u64) * 30 + fr as u64 - dropped;
+                (frames as f64 + subframes) * 1001.0 / 30_000.0
+            }
+            _ => {
+                (3600 * hr as u32 + 60 * mn as u32 + se as u32) as f64
+                    + (fr as f64 + subframes) / self.frame_rate()
+            }
+        }
+    }
 }
 
-impl From<u8> for SmpteFrameSpec {
-    fn from(val: u8) -> Self {
+impl TryFrom<u8> for SmpteFrameSpec {
+    type Error = anyhow::Error;
+
+    fn try_from(val: u8) -> Result<Self> {
         match val {
-            0 => SmpteFrameSpec::F24,
-            1 => SmpteFrameSpec::F25,
-            2 => SmpteFrameSpec::F2997,
-            3 => SmpteFrameSpec::F30,
-            _ => panic!("Invalid SMPTE frame rate"),
+            0 => Ok(SmpteFrameSpec::F24),
+            1 => Ok(SmpteFrameSpec::F25),
+            2 => Ok(SmpteFrameSpec::F2997),
+            3 => Ok(SmpteFrameSpec::F30),
+            _ => Err(anyhow!("Invalid SMPTE frame rate bits: {}", val)),
         }
     }
 }
 
-fn extract_frame_rate_hrs(smpte_offset: &SmpteOffsetValue) -> (f64, u8) {
-    unsafe {
-        let smpte_layout =
-            mem::transmute::<SmpteOffsetValue, SmpteOffsetValueLayout>(*smpte_offset);
-        // shift off the last 6  bits to get the frame rate
-        let mask = 0b0000_0011;
-        let frame_rate_spec = (smpte_layout.hr >> 6) & mask;
-        let fr = SmpteFrameSpec::from(frame_rate_spec).frame_rate();
+/// A SMPTE offset with its frame rate bits split off the hour byte.
+struct SmpteTimecode {
+    spec: SmpteFrameSpec,
+    hr: u8,
+    mn: u8,
+    se: u8,
+    fr: u8,
+    ff: u8,
+}
+
+impl SmpteTimecode {
+    fn seconds(&self) -> f64 {
+        self.spec
+            .timecode_to_seconds(self.hr, self.mn, self.se, self.fr, self.ff)
+    }
+}
+
+fn decode_smpte_offset(smpte_offset: &SmpteOffsetValue) -> Result<SmpteTimecode> {
+    let layout =
+        unsafe { mem::transmute::<SmpteOffsetValue, SmpteOffsetValueLayout>(*smpte_offset) };
+    // the hour byte is 0rrhhhhh: two bits of frame rate above a five bit hour
+    let spec = SmpteFrameSpec::try_from((layout.hr >> 5) & 0b0000_0011)?;
+    Ok(SmpteTimecode {
+        spec,
+        hr: layout.hr & 0b0001_1111,
+        mn: layout.mn,
+        se: layout.se,
+        fr: layout.fr,
+        ff: layout.ff,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const END_OF_TRACK: &[u8] = &[0xFF, 0x2F, 0x00];
+
+    fn write_vlq(out: &mut Vec<u8>, value: u32) {
+        let mut bytes = vec![(value & 0x7F) as u8];
+        let mut rest = value >> 7;
+        while rest > 0 {
+            bytes.push((rest & 0x7F) as u8 | 0x80);
+            rest >>= 7;
+        }
+        out.extend(bytes.iter().rev());
+    }
+
+    // raw track data from (delta ticks, event bytes) pairs, closed with an EndOfTrack
+    fn track(events: &[(u32, &[u8])]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for (delta, bytes) in events {
+            write_vlq(&mut data, *delta);
+            data.extend_from_slice(bytes);
+        }
+        if events.last().map(|(_, bytes)| *bytes) != Some(END_OF_TRACK) {
+            write_vlq(&mut data, 0);
+            data.extend_from_slice(END_OF_TRACK);
+        }
+        data
+    }
+
+    // a format 0, 1 or 2 file with the given division word
+    fn midi_file(format: u16, division: u16, tracks: &[Vec<u8>]) -> MidiFile {
+        let mut bytes = b"MThd".to_vec();
+        bytes.extend_from_slice(&6u32.to_be_bytes());
+        bytes.extend_from_slice(&format.to_be_bytes());
+        bytes.extend_from_slice(&(tracks.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&division.to_be_bytes());
+        for track in tracks {
+            bytes.extend_from_slice(b"MTrk");
+            bytes.extend_from_slice(&(track.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(track);
+        }
+        MidiFile::read(&bytes[..]).unwrap()
+    }
+
+    // a note on channel 1 with a CC on channel 2 while it's held
+    fn note_and_cc() -> MidiFile {
+        midi_file(
+            0,
+            480,
+            &[track(&[
+                (0, &[0x90, 60, 100]),
+                (240, &[0xB1, 7, 64]),
+                (240, &[0x80, 60, 0]),
+            ])],
+        )
+    }
+
+    #[test]
+    fn cc_override_moves_only_control_changes() {
+        let events = Extractor::new(note_and_cc(), None)
+            .unwrap()
+            .with_override_cc_channel(Some(9))
+            .run()
+            .unwrap();
+        let channels: Vec<u8> = events.iter().map(|e| e.channel).collect();
+        assert_eq!(channels, [1, 9, 1]);
+    }
+
+    #[test]
+    fn cc_override_takes_precedence_for_control_changes() {
+        let events = Extractor::new(note_and_cc(), Some(3))
+            .unwrap()
+            .with_override_cc_channel(Some(9))
+            .run()
+            .unwrap();
+        let channels: Vec<u8> = events.iter().map(|e| e.channel).collect();
+        assert_eq!(channels, [3, 9, 3]);
+    }
+
+    #[test]
+    fn drop_frame_timecode_tracks_wall_clock_time() {
+        let seconds = |hr, mn, se, fr| SmpteFrameSpec::F2997.timecode_to_seconds(hr, mn, se, fr, 0);
+        // 01:00:00;00 is frame 107892 once 108 frame numbers have been dropped
+        assert!((seconds(1, 0, 0, 0) - 107_892.0 * 1001.0 / 30_000.0).abs() < 1e-9);
+        assert!((seconds(1, 0, 0, 0) - 3600.0).abs() < 0.004);
+        // ;00 and ;01 don't exist at minute 1, so ;02 directly follows 00:00:59;29
+        assert!((seconds(0, 1, 0, 2) - seconds(0, 0, 59, 29) - 1001.0 / 30_000.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn non_drop_frame_rates_count_whole_frames() {
+        assert_eq!(SmpteFrameSpec::F25.timecode_to_seconds(0, 1, 2, 5, 0), 62.2);
+        assert_eq!(
+            SmpteFrameSpec::F24.timecode_to_seconds(1, 0, 0, 12, 0),
+            3600.5
+        );
+    }
+
+    #[test]
+    fn soft_note_ons_at_or_below_the_threshold_are_offs() {
+        let file = midi_file(
+            0,
+            480,
+            &[track(&[
+                (0, &[0x90, 60, 3]),
+                (0, &[0x90, 62, 5]),
+                (0, &[0x90, 64, 6]),
+                (480, &[0x80, 64, 0]),
+            ])],
+        );
+        let events = Extractor::new(file, None)
+            .unwrap()
+            .with_off_velocity_threshold(5)
+            .run()
+            .unwrap();
+        let messages: Vec<&midi_event::Message> = events.iter().map(|e| &e.message).collect();
+        assert_eq!(
+            messages,
+            [
+                &midi_event::Message::NoteOff(60, 0),
+                &midi_event::Message::NoteOff(62, 0),
+                &midi_event::Message::NoteOn(64, 6),
+                &midi_event::Message::NoteOff(64, 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn velocity_step_rounds_to_the_nearest_step() {
+        assert_eq!(round_to_step(100, 16), 96);
+        assert_eq!(round_to_step(104, 16), 112);
+        assert_eq!(round_to_step(127, 16), 127);
+    }
+
+    fn tempo(bpm: f64) -> Vec<u8> {
+        let micros = (60_000_000.0 / bpm).round() as u32;
+        let mut bytes = vec![0xFF, 0x51, 0x03];
+        bytes.extend_from_slice(&micros.to_be_bytes()[1..]);
+        bytes
+    }
+
+    fn extract(file: MidiFile) -> Vec<midi_event::MidiEvent> {
+        Extractor::new(file, None).unwrap().run().unwrap()
+    }
+
+    #[test]
+    fn tempo_map_replaces_the_file_tempo() {
+        let file = midi_file(
+            0,
+            480,
+            &[track(&[(0, &tempo(60.0)), (480, &[0xB0, 7, 64])])],
+        );
+        let map = TempoMap::parse("0,120").unwrap();
+        let events = Extractor::new(file, None)
+            .unwrap()
+            .with_tempo_map(Some(&map))
+            .unwrap()
+            .run()
+            .unwrap();
+        assert_eq!(events[0].timestamp, 0.5);
+    }
+
+    #[test]
+    fn tempo_map_matches_the_same_embedded_tempo() {
+        let cc: &[u8] = &[0xB0, 7, 64];
+        let embedded = midi_file(0, 480, &[track(&[(0, &tempo(90.0)), (480, cc), (960, cc)])]);
+        let bare = midi_file(0, 480, &[track(&[(480, cc), (960, cc)])]);
+        let map = TempoMap::parse("0,90").unwrap();
+        let mapped = Extractor::new(bare, None)
+            .unwrap()
+            .with_tempo_map(Some(&map))
+            .unwrap()
+            .run()
+            .unwrap();
+        let times = |events: &[midi_event::MidiEvent]| -> Vec<f64> {
+            events.iter().map(|e| e.timestamp).collect()
+        };
+        assert_eq!(times(&mapped), times(&extract(embedded)));
+    }
+
+    fn empty_file() -> MidiFile {
+        midi_file(0, 480, &[track(&[])])
+    }
+
+    #[test]
+    fn raw_channels_are_emitted_one_based() {
+        let extractor = Extractor::new(empty_file(), None).unwrap();
+        assert_eq!(extractor.resolve_channel(0, MessageKind::Note), 1);
+        assert_eq!(extractor.resolve_channel(15, MessageKind::Note), 16);
+    }
+
+    #[test]
+    fn override_channel_is_emitted_as_given() {
+        let extractor = Extractor::new(empty_file(), Some(5)).unwrap();
+        assert_eq!(extractor.resolve_channel(0, MessageKind::Note), 5);
+        assert_eq!(extractor.resolve_channel(15, MessageKind::ControlChange), 5);
+    }
+
+    #[test]
+    fn channel_per_track_uses_the_track_index() {
+        let file = midi_file(
+            1,
+            480,
+            &[
+                track(&[(0, &tempo(120.0)), (0, &[0xB9, 1, 64])]),
+                track(&[(0, &[0xB9, 7, 64])]),
+                track(&[(0, &[0xB9, 10, 64])]),
+            ],
+        );
+        let mut extractor = Extractor::new(file, None)
+            .unwrap()
+            .with_channel_per_track(true);
+        let channels: Vec<u8> = extractor.run().unwrap().iter().map(|e| e.channel).collect();
+        assert_eq!(channels, [1, 2, 3]);
+
+        extractor.current_track = 40;
+        assert_eq!(extractor.resolve_channel(0, MessageKind::Note), 16);
+    }
+
+    #[test]
+    fn note_on_with_velocity_zero_is_a_note_off() {
+        // running status: the second NoteOn has no status byte
+        let file = midi_file(0, 480, &[track(&[(0, &[0x90, 60, 100]), (480, &[60, 0])])]);
+        let events = extract(file);
+        assert_eq!(events[1].message, midi_event::Message::NoteOff(60, 0));
+        assert_eq!(events.len(), 2);
+    }
+
+    #[test]
+    fn only_channel_filters_on_the_source_channel() {
+        let file = midi_file(
+            0,
+            480,
+            &[track(&[
+                (0, &[0xB0, 7, 64]),
+                (0, &[0xB1, 7, 65]),
+                (0, &[0xB9, 7, 66]),
+            ])],
+        );
+        // the override applies after the filter, so it can't change what matches
+        let events = Extractor::new(file, Some(5))
+            .unwrap()
+            .with_only_channel(Some(2))
+            .run()
+            .unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].message, midi_event::Message::ControlChange(7, 65));
+        assert_eq!(events[0].channel, 5);
+    }
+
+    #[test]
+    fn events_from_every_track_come_out_in_time_order() {
+        let file = midi_file(
+            1,
+            480,
+            &[
+                track(&[
+                    (0, &tempo(120.0)),
+                    (960, &[0xB0, 1, 1]),
+                    (960, &[0xB0, 3, 3]),
+                ]),
+                track(&[(480, &[0xB1, 0, 0]), (960, &[0xB1, 2, 2])]),
+            ],
+        );
+        let times: Vec<f64> = extract(file).iter().map(|e| e.timestamp).collect();
+        assert_eq!(times, [0.5, 1.0, 1.5, 2.0]);
+    }
+
+    fn messages(file: MidiFile) -> Vec<midi_event::Message> {
+        extract(file).into_iter().map(|e| e.message).collect()
+    }
+
+    #[test]
+    fn pitch_bend_is_centered_on_zero() {
+        let file = midi_file(
+            0,
+            480,
+            &[track(&[
+                (0, &[0xE0, 0x00, 0x40]),
+                (0, &[0xE0, 0x00, 0x00]),
+                (0, &[0xE0, 0x7F, 0x7F]),
+            ])],
+        );
+        assert_eq!(
+            messages(file),
+            [
+                midi_event::Message::PitchBend(0),
+                midi_event::Message::PitchBend(-8192),
+                midi_event::Message::PitchBend(8191)
+            ]
+        );
+    }
+
+    fn note_ons(notes: &[u8]) -> MidiFile {
+        let events: Vec<[u8; 3]> = notes.iter().map(|n| [0x90, *n, 100]).collect();
+        let pairs: Vec<(u32, &[u8])> = events.iter().map(|e| (0, &e[..])).collect();
+        midi_file(0, 480, &[track(&pairs)])
+    }
+
+    fn run_notes(mut extractor: Extractor) -> Vec<u8> {
+        let events = extractor.run().unwrap();
+        events
+            .iter()
+            .filter_map(|e| match e.message {
+                midi_event::Message::NoteOn(note, _) => Some(note),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn transpose_shifts_and_clamps_notes() {
+        let up = Extractor::new(note_ons(&[0, 60, 120]), None)
+            .unwrap()
+            .with_transpose(12);
+        assert_eq!(run_notes(up), [12, 72, 127]);
+        let down = Extractor::new(note_ons(&[5, 60]), None)
+            .unwrap()
+            .with_transpose(-12);
+        assert_eq!(run_notes(down), [0, 48]);
+    }
+
+    #[test]
+    fn cc_scale_maps_the_full_range() {
+        assert_eq!(scale_cc(0, 20, 100), 20);
+        assert_eq!(scale_cc(127, 20, 100), 100);
+        assert_eq!(scale_cc(64, 0, 127), 64);
+        // min above max inverts
+        assert_eq!(scale_cc(0, 127, 0), 127);
+        assert_eq!(scale_cc(127, 127, 0), 0);
+    }
+
+    #[test]
+    fn channel_remap_moves_listed_channels_only() {
+        let file = midi_file(0, 480, &[track(&[(0, &[0xB0, 7, 1]), (0, &[0xB1, 7, 2])])]);
+        let events = Extractor::new(file, None)
+            .unwrap()
+            .with_channel_remap(HashMap::from([(0, 10)]))
+            .run()
+            .unwrap();
+        let channels: Vec<u8> = events.iter().map(|e| e.channel).collect();
+        assert_eq!(channels, [10, 2]);
+    }
+
+    #[test]
+    fn override_channel_must_be_one_to_sixteen() {
+        assert!(Extractor::new(empty_file(), Some(0)).is_err());
+        assert!(Extractor::new(empty_file(), Some(17)).is_err());
+        assert!(Extractor::new(empty_file(), Some(1)).is_ok());
+        assert!(Extractor::new(empty_file(), Some(16)).is_ok());
+    }
+
+    fn time_signature(numerator: u8, denominator: u8) -> Vec<u8> {
+        let power = denominator.trailing_zeros() as u8;
+        vec![0xFF, 0x58, 0x04, numerator, power, 24, 8]
+    }
+
+    // (bar, beat, tick) of each event
+    fn positions(file: MidiFile) -> Vec<(u32, u32, u32)> {
+        extract(file)
+            .iter()
+            .map(|e| {
+                let pos = e.bar_beat.unwrap();
+                (pos.bar, pos.beat, pos.tick)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn bar_beat_in_four_four() {
+        let file = midi_file(
+            0,
+            480,
+            &[track(&[
+                (0, &[0xB0, 1, 0]),
+                (1440, &[0xB0, 1, 1]),
+                (500, &[0xB0, 1, 2]),
+            ])],
+        );
+        assert_eq!(positions(file), [(1, 1, 0), (1, 4, 0), (2, 1, 20)]);
+    }
+
+    #[test]
+    fn bar_beat_in_three_four() {
+        let file = midi_file(
+            0,
+            480,
+            &[track(&[
+                (0, &time_signature(3, 4)),
+                (1440, &[0xB0, 1, 1]),
+                (960, &[0xB0, 1, 2]),
+            ])],
+        );
+        assert_eq!(positions(file), [(2, 1, 0), (2, 3, 0)]);
+    }
+
+    #[test]
+    fn bar_beat_in_six_thirty_two() {
+        // a 32nd note beat is 60 ticks at 480 PPQ, so a bar is 360 ticks
+        let file = midi_file(
+            0,
+            480,
+            &[track(&[
+                (0, &time_signature(6, 32)),
+                (360, &[0xB0, 1, 1]),
+                (90, &[0xB0, 1, 2]),
+            ])],
+        );
+        assert_eq!(positions(file), [(2, 1, 0), (2, 2, 30)]);
+    }
+
+    #[test]
+    fn meter_change_starts_a_new_bar() {
+        // one bar of 4/4, then 3/4
+        let file = midi_file(
+            0,
+            480,
+            &[track(&[
+                (1920, &time_signature(3, 4)),
+                (1440, &[0xB0, 1, 1]),
+            ])],
+        );
+        assert_eq!(positions(file), [(3, 1, 0)]);
+    }
+
+    #[test]
+    fn simultaneous_offs_come_before_ons_of_other_notes() {
+        let file = midi_file(
+            0,
+            480,
+            &[track(&[
+                (0, &[0x90, 60, 100]),
+                (480, &[0x90, 62, 100]),
+                (0, &[0x80, 60, 0]),
+                (480, &[0x80, 62, 0]),
+            ])],
+        );
+        let off_first = extract(file.clone());
+        assert_eq!(off_first[1].message, midi_event::Message::NoteOff(60, 0));
+        assert_eq!(off_first[2].message, midi_event::Message::NoteOn(62, 100));
+
+        let on_first = Extractor::new(file, None)
+            .unwrap()
+            .with_note_off_first(false)
+            .run()
+            .unwrap();
+        assert_eq!(on_first[1].message, midi_event::Message::NoteOn(62, 100));
+        assert_eq!(on_first[2].message, midi_event::Message::NoteOff(60, 0));
+    }
+
+    // a CC before the only tempo change (60 BPM at tick 480), and one after it
+    fn late_tempo() -> MidiFile {
+        midi_file(
+            0,
+            480,
+            &[track(&[
+                (0, &[0xB0, 1, 0]),
+                (480, &tempo(60.0)),
+                (480, &[0xB0, 1, 1]),
+            ])],
+        )
+    }
+
+    #[test]
+    fn events_before_the_first_tempo_use_120_bpm() {
+        let times: Vec<f64> = extract(late_tempo()).iter().map(|e| e.timestamp).collect();
+        assert_eq!(times, [0.0, 1.5]);
+    }
+
+    #[test]
+    fn assume_first_tempo_applies_it_from_the_start() {
+        let events = Extractor::new(late_tempo(), None)
+            .unwrap()
+            .with_assume_first_tempo(true)
+            .run()
+            .unwrap();
+        let times: Vec<f64> = events.iter().map(|e| e.timestamp).collect();
+        assert_eq!(times, [0.0, 2.0]);
+    }
+
+    #[test]
+    fn require_tempo_rejects_a_late_tempo() {
+        let result = Extractor::new(late_tempo(), None)
+            .unwrap()
+            .with_require_tempo(true)
+            .run();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn note_range_keeps_only_notes_inside_it() {
+        let extractor = Extractor::new(note_ons(&[35, 36, 50, 51]), None)
+            .unwrap()
+            .with_note_range(36, 50);
+        assert_eq!(run_notes(extractor), [36, 50]);
+    }
+
+    #[test]
+    fn note_range_applies_after_transposing() {
+        let extractor = Extractor::new(note_ons(&[24, 36]), None)
+            .unwrap()
+            .with_transpose(12)
+            .with_note_range(36, 40);
+        assert_eq!(run_notes(extractor), [36]);
+    }
+
+    #[test]
+    fn quantize_snaps_to_the_nearest_grid_point() {
+        // 1/16 at 480 PPQ is 120 ticks, 0.125s at 120 BPM
+        let file = midi_file(
+            0,
+            480,
+            &[track(&[
+                (59, &[0xB0, 1, 0]),
+                (2, &[0xB0, 1, 1]),
+                (120, &[0xB0, 1, 2]),
+            ])],
+        );
+        let events = Extractor::new(file, None)
+            .unwrap()
+            .with_quantize(Some((1, 16)))
+            .run()
+            .unwrap();
+        let times: Vec<f64> = events.iter().map(|e| e.timestamp).collect();
+        assert_eq!(times, [0.0, 0.125, 0.25]);
+    }
+
+    #[test]
+    fn quantize_never_snaps_an_off_onto_its_on() {
+        let file = midi_file(
+            0,
+            480,
+            &[track(&[(0, &[0x90, 60, 100]), (30, &[0x80, 60, 0])])],
+        );
+        let events = Extractor::new(file, None)
+            .unwrap()
+            .with_quantize(Some((1, 16)))
+            .run()
+            .unwrap();
+        let times: Vec<f64> = events.iter().map(|e| e.timestamp).collect();
+        assert_eq!(times, [0.0, 0.125]);
+    }
+
+    #[test]
+    fn channel_base_zero_keeps_file_channels() {
+        let extractor = Extractor::new(empty_file(), None)
+            .unwrap()
+            .with_channel_base(0);
+        assert_eq!(extractor.resolve_channel(0, MessageKind::Note), 0);
+        assert_eq!(extractor.resolve_channel(15, MessageKind::Note), 15);
+    }
+
+    #[test]
+    fn channel_base_doesnt_shift_explicit_channels() {
+        let overridden = Extractor::new(empty_file(), Some(5))
+            .unwrap()
+            .with_channel_base(0);
+        assert_eq!(overridden.resolve_channel(0, MessageKind::Note), 5);
+        let remapped = Extractor::new(empty_file(), None)
+            .unwrap()
+            .with_channel_base(0)
+            .with_channel_remap(HashMap::from([(3, 9)]));
+        assert_eq!(remapped.resolve_channel(3, MessageKind::ControlChange), 9);
+        assert_eq!(remapped.resolve_channel(4, MessageKind::ControlChange), 4);
+    }
+
+    #[test]
+    fn velocity_curves_keep_the_ends_and_never_silence() {
+        assert_eq!(apply_velocity_curve(100, VelocityCurve::Linear), 100);
+        assert_eq!(apply_velocity_curve(127, VelocityCurve::Exp), 127);
+        assert_eq!(apply_velocity_curve(64, VelocityCurve::Exp), 32);
+        assert_eq!(apply_velocity_curve(32, VelocityCurve::Log), 64);
+        assert_eq!(apply_velocity_curve(1, VelocityCurve::Exp), 1);
+        assert_eq!(apply_velocity_curve(1, VelocityCurve::Invert), 126);
+        // inverting full velocity would give 0, which is a NoteOff
+        assert_eq!(apply_velocity_curve(127, VelocityCurve::Invert), 1);
+    }
+
+    #[test]
+    fn velocity_curve_leaves_note_offs_alone() {
+        let file = midi_file(
+            0,
+            480,
+            &[track(&[(0, &[0x90, 60, 127]), (480, &[0x80, 60, 64])])],
+        );
+        let events = Extractor::new(file, None)
+            .unwrap()
+            .with_velocity_curve(VelocityCurve::Invert)
+            .run()
+            .unwrap();
+        assert_eq!(events[0].message, midi_event::Message::NoteOn(60, 1));
+        assert_eq!(events[1].message, midi_event::Message::NoteOff(60, 0));
+    }
+
+    // SMPTE offset meta event with the frame rate bits in the hour byte
+    fn smpte_offset(rate: u8, hr: u8, mn: u8, se: u8, fr: u8) -> Vec<u8> {
+        vec![0xFF, 0x54, 0x05, rate << 5 | hr, mn, se, fr, 0]
+    }
+
+    #[test]
+    fn smpte_offset_of_one_second_delays_every_event() {
+        let file = midi_file(
+            0,
+            480,
+            &[track(&[
+                (0, &smpte_offset(0, 0, 0, 1, 0)),
+                (0, &[0x90, 60, 100]),
+                (480, &[0x80, 60, 0]),
+            ])],
+        );
+        let events = Extractor::new(file, None)
+            .unwrap()
+            .with_apply_smpte_offset(true)
+            .run()
+            .unwrap();
+        let times: Vec<f64> = events.iter().map(|e| e.timestamp).collect();
+        assert_eq!(times, [1.0, 1.5]);
+    }
+
+    #[test]
+    fn smpte_offset_is_ignored_unless_asked_for() {
+        let file = midi_file(
+            0,
+            480,
+            &[track(&[
+                (0, &smpte_offset(0, 0, 0, 10, 0)),
+                (0, &[0xB0, 1, 0]),
+            ])],
+        );
+        assert_eq!(extract(file)[0].timestamp, 0.0);
+    }
+
+    // a note that is never turned off, in a track that ends at 1s
+    fn held_note() -> MidiFile {
+        midi_file(
+            0,
+            480,
+            &[track(&[
+                (0, &[0x90, 60, 100]),
+                (480, &[0xB0, 1, 0]),
+                (480, END_OF_TRACK),
+            ])],
+        )
+    }
+
+    #[test]
+    fn held_notes_are_turned_off_at_the_end_of_the_track() {
+        let events = extract(held_note());
+        let last = events.last().unwrap();
+        assert_eq!(last.message, midi_event::Message::NoteOff(60, 0));
+        assert_eq!(last.timestamp, 1.0);
+        assert_eq!(events.len(), 3);
+    }
+
+    #[test]
+    fn flushing_held_notes_can_be_turned_off() {
+        let events = Extractor::new(held_note(), None)
+            .unwrap()
+            .with_flush_held_notes(false)
+            .run()
+            .unwrap();
+        assert_eq!(events.len(), 2);
+    }
+
+    #[test]
+    fn duration_comes_from_the_latest_end_of_track() {
+        let file = midi_file(
+            1,
+            480,
+            &[
+                track(&[(0, &tempo(60.0)), (480, END_OF_TRACK)]),
+                track(&[(0, &[0xB0, 1, 0]), (1440, END_OF_TRACK)]),
+            ],
+        );
+        let mut extractor = Extractor::new(file, None).unwrap();
+        assert_eq!(extractor.duration(), None);
+        extractor.run().unwrap();
+        // trailing silence after the last event counts
+        assert_eq!(extractor.duration(), Some(3.0));
+    }
+
+    #[test]
+    fn override_scope_limits_the_override() {
+        let channels = |scope| {
+            let events = Extractor::new(note_and_cc(), Some(7))
+                .unwrap()
+                .with_override_scope(scope)
+                .run()
+                .unwrap();
+            events.iter().map(|e| e.channel).collect::<Vec<u8>>()
+        };
+        assert_eq!(channels(OverrideScope::All), [7, 7, 7]);
+        assert_eq!(channels(OverrideScope::Notes), [7, 2, 7]);
+        assert_eq!(channels(OverrideScope::ControlChanges), [1, 7, 1]);
+    }
+
+    #[test]
+    fn forced_bpm_ignores_file_tempo_and_tempo_map() {
+        let file = midi_file(
+            0,
+            480,
+            &[track(&[
+                (0, &tempo(120.0)),
+                (480, &tempo(30.0)),
+                (480, &[0xB0, 1, 0]),
+            ])],
+        );
+        let map = TempoMap::parse("0,200").unwrap();
+        let events = Extractor::new(file, None)
+            .unwrap()
+            .with_tempo_map(Some(&map))
+            .unwrap()
+            .with_force_bpm(Some(60.0))
+            .run()
+            .unwrap();
+        assert_eq!(events[0].timestamp, 2.0);
+    }
+
+    #[test]
+    fn type2_sequences_play_back_to_back_with_their_own_tempo() {
+        let first = track(&[(0, &tempo(60.0)), (0, &[0xB0, 1, 0]), (480, END_OF_TRACK)]);
+        // no tempo of its own: 120 BPM, not the first sequence's 60
+        let second = track(&[(0, &[0xB0, 1, 1]), (480, &[0xB0, 1, 2])]);
+        let events = Extractor::new(midi_file(2, 480, &[first, second]), None)
+            .unwrap()
+            .with_track_gap_ms(500)
+            .run()
+            .unwrap();
+        let times: Vec<(f64, usize)> = events.iter().map(|e| (e.timestamp, e.track)).collect();
+        assert_eq!(times, [(0.0, 0), (1.5, 1), (2.0, 1)]);
+    }
+
+    #[test]
+    fn velocity_step_never_silences_a_note() {
+        assert_eq!(round_to_step(5, 16), 16);
+        assert_eq!(round_to_step(1, 200), 127);
+    }
+
+    #[test]
+    fn events_sharing_a_rounded_time_keep_their_tick_order() {
+        // at 120 BPM and 480 PPQ, ticks 1 and 2 are 1ms apart: both print as 00:00.00
+        let file = midi_file(
+            1,
+            480,
+            &[
+                track(&[(0, &tempo(120.0)), (2, &[0xB0, 7, 100])]),
+                track(&[(1, &[0xB0, 10, 64])]),
+            ],
+        );
+        let events = extract(file);
+        let messages: Vec<&midi_event::Message> = events.iter().map(|e| &e.message).collect();
+        assert_eq!(
+            messages,
+            [
+                &midi_event::Message::ControlChange(10, 64),
+                &midi_event::Message::ControlChange(7, 100)
+            ]
+        );
+    }
+
+    #[test]
+    fn zero_length_note_keeps_its_on_before_its_off() {
+        let file = midi_file(
+            0,
+            480,
+            &[track(&[
+                (0, &[0x90, 60, 100]),
+                (0, &[0x80, 60, 0]),
+                (480, &[0x90, 62, 100]),
+                (480, &[0x80, 62, 0]),
+            ])],
+        );
+        let events = extract(file);
+        let messages: Vec<&midi_event::Message> = events.iter().map(|e| &e.message).collect();
+        // no NoteOff is added at the end: nothing is left held
+        assert_eq!(
+            messages,
+            [
+                &midi_event::Message::NoteOn(60, 100),
+                &midi_event::Message::NoteOff(60, 0),
+                &midi_event::Message::NoteOn(62, 100),
+                &midi_event::Message::NoteOff(62, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn smpte_offset_is_not_carried_into_later_type2_sequences() {
+        // SMPTE offset of one second at 25 fps
+        let offset: &[u8] = &[0xFF, 0x54, 0x05, 0x20, 0, 1, 0, 0];
+        let sequence = track(&[(0, offset), (0, &[0x90, 60, 100]), (480, &[0x80, 60, 0])]);
+        let file = midi_file(2, 480, &[sequence.clone(), sequence]);
+        let events = Extractor::new(file, None)
+            .unwrap()
+            .with_apply_smpte_offset(true)
+            .run()
+            .unwrap();
+        let times: Vec<f64> = events.iter().map(|e| e.timestamp).collect();
+        // each sequence is half a second long and starts one second into its own clock
+        assert_eq!(times, [1.0, 1.5, 1.5, 2.0]);
+    }
+
+    fn marker(name: &str) -> Vec<u8> {
+        let mut bytes = vec![0xFF, 0x06, name.len() as u8];
+        bytes.extend_from_slice(name.as_bytes());
+        bytes
+    }
 
-        let hr_mask = 0b0001_1111;
-        let hr = smpte_layout.hr & hr_mask;
+    #[test]
+    fn track_filter_keeps_markers_from_other_tracks() {
+        let conductor = track(&[(0, &tempo(120.0)), (960, &marker("Chorus"))]);
+        let drums = track(&[(0, &[0x99, 36, 100]), (480, &[0x89, 36, 0])]);
+        let keys = track(&[(0, &[0x90, 60, 100]), (480, &[0x80, 60, 0])]);
+        let mut extractor = Extractor::new(midi_file(1, 480, &[conductor, drums, keys]), None)
+            .unwrap()
+            .with_tracks(vec![1]);
+        let events = extractor.run().unwrap();
+        assert!(events.iter().all(|e| e.track == 1));
+        assert_eq!(events.len(), 2);
+        let markers: Vec<(f64, &str)> = extractor
+            .markers()
+            .iter()
+            .map(|m| (m.timestamp, m.name.as_str()))
+            .collect();
+        assert_eq!(markers, [(1.0, "Chorus")]);
+    }
+
+    #[test]
+    fn quantized_event_shows_the_bar_it_snaps_into() {
+        // a hit just before bar 2 snaps onto its downbeat
+        let file = midi_file(0, 480, &[track(&[(1910, &[0x99, 36, 100])])]);
+        let events = Extractor::new(file, None)
+            .unwrap()
+            .with_quantize(Some((1, 4)))
+            .run()
+            .unwrap();
+        let bar_beat = events[0].bar_beat.unwrap();
+        assert_eq!((bar_beat.bar, bar_beat.beat, bar_beat.tick), (2, 1, 0));
+    }
 
-        (fr, hr)
+    #[test]
+    fn quantize_grid_that_overflows_is_ignored() {
+        let file = midi_file(0, 0x7FFF, &[track(&[(10, &[0x99, 36, 100])])]);
+        let extractor = Extractor::new(file, None)
+            .unwrap()
+            .with_quantize(Some((u32::MAX, 1)));
+        assert_eq!(extractor.quantize, None);
+    }
+
+    #[test]
+    fn smpte_offset_reads_the_frame_rate_bits() {
+        let decode = |bytes: &[u8]| {
+            let file = midi_file(0, 480, &[track(&[(0, bytes), (0, &[0xB0, 1, 0])])]);
+            let mut extractor = Extractor::new(file, None)
+                .unwrap()
+                .with_apply_smpte_offset(true);
+            extractor.run().unwrap()[0].timestamp
+        };
+        // 00:00:01:12 is 1.5s at 24 fps and 1.48s at 25 fps
+        assert_eq!(decode(&smpte_offset(0, 0, 0, 1, 12)), 1.5);
+        assert_eq!(decode(&smpte_offset(1, 0, 0, 1, 12)), 1.48);
+        assert_eq!(decode(&smpte_offset(3, 1, 0, 0, 15)), 3600.5);
     }
 }
@@ -0,0 +1,124 @@
+use crate::formatter::stage_traxx_formatter::{
+    format_midi_time, message_token, DEFAULT_TIME_PRECISION,
+};
+use crate::formatter::MidiFormatter;
+use crate::midi_event::MidiEvent;
+
+/// Emits one line per timestamp with every message at that time, e.g.
+/// `00:10.000 N36.100@1 CC7.64@1`.
+pub struct CompactFormatter {
+    delimiter: String,
+    always_hours: bool,
+    precision: u8,
+    note_names: bool,
+}
+
+impl CompactFormatter {
+    pub fn new(delimiter: &str) -> Self {
+        Self {
+            delimiter: delimiter.to_string(),
+            always_hours: false,
+            precision: DEFAULT_TIME_PRECISION,
+            note_names: false,
+        }
+    }
+
+    /// Number of fractional digits in timestamps (0..=9).
+    pub fn with_time_precision(mut self, precision: u8) -> Self {
+        self.precision = precision.min(9);
+        self
+    }
+
+    /// Always include hours in timestamps.
+    pub fn with_always_hours(mut self, always_hours: bool) -> Self {
+        self.always_hours = always_hours;
+        self
+    }
+
+    /// Write notes by pitch name (`NC4.100`) instead of number.
+    pub fn with_note_names(mut self, note_names: bool) -> Self {
+        self.note_names = note_names;
+        self
+    }
+
+    /// Formats a group of events that share a timestamp as a single line.
+    pub fn format_group(&self, events: &[MidiEvent]) -> String {
+        let Some(first) = events.first() else {
+            return String::new();
+        };
+        self.format_group_at(first.timestamp, events)
+    }
+
+    /// Like `format_group`, but stamps the line with `timestamp` instead of the events' own
+    /// time, e.g. the delta since the previous line.
+    pub fn format_group_at(&self, timestamp: f64, events: &[MidiEvent]) -> String {
+        if events.is_empty() {
+            return String::new();
+        }
+        let tokens: Vec<String> = events
+            .iter()
+            .map(|e| message_token(e, self.note_names))
+            .collect();
+        format!(
+            "{} {}",
+            format_midi_time(timestamp, self.always_hours, self.precision),
+            tokens.join(&self.delimiter)
+        )
+    }
+}
+
+impl MidiFormatter for CompactFormatter {
+    fn format(&self, event: &MidiEvent) -> String {
+        self.format_group(std::slice::from_ref(event))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi_event::Message;
+
+    fn note_on(timestamp: f64, note: u8) -> MidiEvent {
+        MidiEvent {
+            timestamp,
+            message: Message::NoteOn(note, 100),
+            channel: 1,
+            track: 0,
+            bar_beat: None,
+        }
+    }
+
+    #[test]
+    fn three_messages_share_one_line() {
+        let mut cc = note_on(10.0, 0);
+        cc.message = Message::ControlChange(7, 64);
+        let events = [note_on(10.0, 36), note_on(10.0, 38), cc];
+        assert_eq!(
+            CompactFormatter::new(", ").format_group(&events),
+            "00:10.000 N36.100@1, N38.100@1, CC7.64@1"
+        );
+    }
+
+    #[test]
+    fn groups_events_on_one_line() {
+        let formatter = CompactFormatter::new(" ");
+        let events = [note_on(10.0, 36), note_on(10.0, 38)];
+        assert_eq!(
+            formatter.format_group(&events),
+            "00:10.000 N36.100@1 N38.100@1"
+        );
+    }
+
+    #[test]
+    fn honours_time_and_note_name_settings() {
+        let formatter = CompactFormatter::new(",")
+            .with_time_precision(1)
+            .with_always_hours(true)
+            .with_note_names(true);
+        let events = [note_on(10.0, 60), note_on(10.0, 62)];
+        assert_eq!(
+            formatter.format_group(&events),
+            "00:00:10.0 NC4.100@1,ND4.100@1"
+        );
+    }
+}
@@ -0,0 +1,70 @@
+use crate::formatter::{message_fields, MidiFormatter};
+use crate::midi_event::MidiEvent;
+
+/// Emits one CSV row per event: `timestamp_seconds,message_type,arg1,arg2,channel`,
+/// e.g. `46.700,control_change,1,62,4`. Messages with a single argument leave `arg2` empty.
+#[derive(Default)]
+pub struct CsvFormatter {}
+
+impl CsvFormatter {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl MidiFormatter for CsvFormatter {
+    fn header(&self) -> Option<String> {
+        Some("timestamp_seconds,message_type,arg1,arg2,channel".to_string())
+    }
+
+    fn format(&self, event: &MidiEvent) -> String {
+        let (kind, arg1, arg2) = message_fields(&event.message);
+        format!(
+            "{:.3},{},{},{},{}",
+            event.timestamp, kind, arg1, arg2, event.channel
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi_event::Message;
+
+    fn event(timestamp: f64, message: Message) -> MidiEvent {
+        MidiEvent {
+            timestamp,
+            message,
+            channel: 4,
+            track: 0,
+            bar_beat: None,
+        }
+    }
+
+    #[test]
+    fn header_matches_the_row_layout() {
+        let formatter = CsvFormatter::new();
+        assert_eq!(
+            formatter.header().as_deref(),
+            Some("timestamp_seconds,message_type,arg1,arg2,channel")
+        );
+        assert_eq!(
+            formatter.format(&event(46.7, Message::ControlChange(1, 62))),
+            "46.700,control_change,1,62,4"
+        );
+    }
+
+    #[test]
+    fn rows_never_need_quoting() {
+        // single-argument messages leave arg2 empty rather than dropping the column
+        let formatter = CsvFormatter::new();
+        assert_eq!(
+            formatter.format(&event(1.0, Message::ProgramChange(5))),
+            "1.000,program_change,5,,4"
+        );
+        assert_eq!(
+            formatter.format(&event(0.25, Message::PitchBend(-8192))),
+            "0.250,pitch_bend,-8192,,4"
+        );
+    }
+}
@@ -0,0 +1,95 @@
+use crate::formatter::MidiFormatter;
+use crate::midi_event::{Message, MidiEvent};
+
+/// Emits one JSON object per event (JSON Lines), e.g.
+/// `{"timestamp":46.7,"type":"control_change","channel":4,"controller":1,"value":62}`
+#[derive(Default)]
+pub struct JsonFormatter {}
+
+impl JsonFormatter {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl MidiFormatter for JsonFormatter {
+    fn format(&self, event: &MidiEvent) -> String {
+        let (kind, fields) = match event.message {
+            Message::NoteOn(note, velocity) => (
+                "note_on",
+                format!("\"note\":{},\"velocity\":{}", note, velocity),
+            ),
+            Message::NoteOff(note, velocity) => (
+                "note_off",
+                format!("\"note\":{},\"velocity\":{}", note, velocity),
+            ),
+            Message::ControlChange(controller, value) => (
+                "control_change",
+                format!("\"controller\":{},\"value\":{}", controller, value),
+            ),
+            Message::ControlChange14(controller, value) => (
+                "control_change_14bit",
+                format!("\"controller\":{},\"value\":{}", controller, value),
+            ),
+            Message::ProgramChange(program) => {
+                ("program_change", format!("\"program\":{}", program))
+            }
+            Message::PitchBend(value) => ("pitch_bend", format!("\"value\":{}", value)),
+            Message::ChannelPressure(pressure) => {
+                ("channel_pressure", format!("\"pressure\":{}", pressure))
+            }
+            Message::PolyPressure(note, pressure) => (
+                "poly_pressure",
+                format!("\"note\":{},\"pressure\":{}", note, pressure),
+            ),
+            Message::Note(note, velocity, duration) => (
+                "note",
+                format!(
+                    "\"note\":{},\"velocity\":{},\"duration\":{}",
+                    note, velocity, duration
+                ),
+            ),
+        };
+        format!(
+            "{{\"timestamp\":{},\"type\":\"{}\",\"channel\":{},{}}}",
+            event.timestamp, kind, event.channel, fields
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(timestamp: f64, message: Message) -> MidiEvent {
+        MidiEvent {
+            timestamp,
+            message,
+            channel: 4,
+            track: 0,
+            bar_beat: None,
+        }
+    }
+
+    #[test]
+    fn fields_are_named_after_the_message() {
+        let formatter = JsonFormatter::new();
+        assert_eq!(
+            formatter.format(&event(46.7, Message::ControlChange(1, 62))),
+            r#"{"timestamp":46.7,"type":"control_change","channel":4,"controller":1,"value":62}"#
+        );
+        assert_eq!(
+            formatter.format(&event(0.5, Message::NoteOn(60, 100))),
+            r#"{"timestamp":0.5,"type":"note_on","channel":4,"note":60,"velocity":100}"#
+        );
+    }
+
+    #[test]
+    fn values_are_bare_json_numbers() {
+        // every value is a number and every string a fixed name, so nothing needs escaping
+        assert_eq!(
+            JsonFormatter::new().format(&event(2.0, Message::ProgramChange(5))),
+            r#"{"timestamp":2,"type":"program_change","channel":4,"program":5}"#
+        );
+    }
+}
@@ -1,9 +1,302 @@
-use crate::midi_event::MidiEvent;
+use crate::midi_event::{Message, MidiEvent};
 
+mod compact_formatter;
+mod csv_formatter;
+mod json_formatter;
+mod reaper_marker_formatter;
 mod stage_traxx_formatter;
+mod template_formatter;
 
-pub use stage_traxx_formatter::StageTraxxFormatter;
+pub use compact_formatter::CompactFormatter;
+pub use csv_formatter::CsvFormatter;
+pub use json_formatter::JsonFormatter;
+pub use reaper_marker_formatter::ReaperMarkerFormatter;
+pub use stage_traxx_formatter::{parse_midi_time, StageTraxxFormatter};
+pub use template_formatter::TemplateFormatter;
 
 pub trait MidiFormatter {
+    /// Metadata lines to emit once at the very top of the output (e.g. a title comment).
+    fn preamble(&self) -> Option<String> {
+        None
+    }
+
+    /// A line to emit once before any events (e.g. a CSV header row).
+    fn header(&self) -> Option<String> {
+        None
+    }
+
     fn format(&self, event: &MidiEvent) -> String;
 }
+
+pub(crate) const NOTE_NAMES: [&str; 12] = [
+    "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+];
+
+/// Pitch name with octave for a MIDI note number, using MIDI 60 = C4 (so 0 is C-1).
+pub fn note_name(note: u8) -> String {
+    let octave = (note / 12) as i8 - 1;
+    format!("{}{}", NOTE_NAMES[(note % 12) as usize], octave)
+}
+
+// General MIDI level 1 program names, indexed by 0-based program number
+const GM_INSTRUMENT_NAMES: [&str; 128] = [
+    "Acoustic Grand Piano",
+    "Bright Acoustic Piano",
+    "Electric Grand Piano",
+    "Honky-tonk Piano",
+    "Electric Piano 1",
+    "Electric Piano 2",
+    "Harpsichord",
+    "Clavi",
+    "Celesta",
+    "Glockenspiel",
+    "Music Box",
+    "Vibraphone",
+    "Marimba",
+    "Xylophone",
+    "Tubular Bells",
+    "Dulcimer",
+    "Drawbar Organ",
+    "Percussive Organ",
+    "Rock Organ",
+    "Church Organ",
+    "Reed Organ",
+    "Accordion",
+    "Harmonica",
+    "Tango Accordion",
+    "Acoustic Guitar (nylon)",
+    "Acoustic Guitar (steel)",
+    "Electric Guitar (jazz)",
+    "Electric Guitar (clean)",
+    "Electric Guitar (muted)",
+    "Overdriven Guitar",
+    "Distortion Guitar",
+    "Guitar Harmonics",
+    "Acoustic Bass",
+    "Electric Bass (finger)",
+    "Electric Bass (pick)",
+    "Fretless Bass",
+    "Slap Bass 1",
+    "Slap Bass 2",
+    "Synth Bass 1",
+    "Synth Bass 2",
+    "Violin",
+    "Viola",
+    "Cello",
+    "Contrabass",
+    "Tremolo Strings",
+    "Pizzicato Strings",
+    "Orchestral Harp",
+    "Timpani",
+    "String Ensemble 1",
+    "String Ensemble 2",
+    "Synth Strings 1",
+    "Synth Strings 2",
+    "Choir Aahs",
+    "Voice Oohs",
+    "Synth Voice",
+    "Orchestra Hit",
+    "Trumpet",
+    "Trombone",
+    "Tuba",
+    "Muted Trumpet",
+    "French Horn",
+    "Brass Section",
+    "Synth Brass 1",
+    "Synth Brass 2",
+    "Soprano Sax",
+    "Alto Sax",
+    "Tenor Sax",
+    "Baritone Sax",
+    "Oboe",
+    "English Horn",
+    "Bassoon",
+    "Clarinet",
+    "Piccolo",
+    "Flute",
+    "Recorder",
+    "Pan Flute",
+    "Blown Bottle",
+    "Shakuhachi",
+    "Whistle",
+    "Ocarina",
+    "Lead 1 (square)",
+    "Lead 2 (sawtooth)",
+    "Lead 3 (calliope)",
+    "Lead 4 (chiff)",
+    "Lead 5 (charang)",
+    "Lead 6 (voice)",
+    "Lead 7 (fifths)",
+    "Lead 8 (bass + lead)",
+    "Pad 1 (new age)",
+    "Pad 2 (warm)",
+    "Pad 3 (polysynth)",
+    "Pad 4 (choir)",
+    "Pad 5 (bowed)",
+    "Pad 6 (metallic)",
+    "Pad 7 (halo)",
+    "Pad 8 (sweep)",
+    "FX 1 (rain)",
+    "FX 2 (soundtrack)",
+    "FX 3 (crystal)",
+    "FX 4 (atmosphere)",
+    "FX 5 (brightness)",
+    "FX 6 (goblins)",
+    "FX 7 (echoes)",
+    "FX 8 (sci-fi)",
+    "Sitar",
+    "Banjo",
+    "Shamisen",
+    "Koto",
+    "Kalimba",
+    "Bag pipe",
+    "Fiddle",
+    "Shanai",
+    "Tinkle Bell",
+    "Agogo",
+    "Steel Drums",
+    "Woodblock",
+    "Taiko Drum",
+    "Melodic Tom",
+    "Synth Drum",
+    "Reverse Cymbal",
+    "Guitar Fret Noise",
+    "Breath Noise",
+    "Seashore",
+    "Bird Tweet",
+    "Telephone Ring",
+    "Helicopter",
+    "Applause",
+    "Gunshot",
+];
+
+/// General MIDI instrument name for a 0-based program number (0 is "Acoustic Grand Piano").
+pub fn gm_instrument_name(program: u8) -> &'static str {
+    GM_INSTRUMENT_NAMES[(program & 0x7F) as usize]
+}
+
+/// Name of a standard assigned MIDI controller (1 is "Modulation"), or None for controllers
+/// the MIDI spec leaves undefined.
+pub fn cc_name(controller: u8) -> Option<&'static str> {
+    let name = match controller {
+        0 => "Bank Select",
+        1 => "Modulation",
+        2 => "Breath Controller",
+        4 => "Foot Controller",
+        5 => "Portamento Time",
+        6 => "Data Entry",
+        7 => "Volume",
+        8 => "Balance",
+        10 => "Pan",
+        11 => "Expression",
+        12 => "Effect Control 1",
+        13 => "Effect Control 2",
+        16 => "General Purpose 1",
+        17 => "General Purpose 2",
+        18 => "General Purpose 3",
+        19 => "General Purpose 4",
+        32 => "Bank Select LSB",
+        38 => "Data Entry LSB",
+        64 => "Sustain",
+        65 => "Portamento",
+        66 => "Sostenuto",
+        67 => "Soft Pedal",
+        68 => "Legato Footswitch",
+        69 => "Hold 2",
+        70 => "Sound Variation",
+        71 => "Resonance",
+        72 => "Release Time",
+        73 => "Attack Time",
+        74 => "Brightness",
+        75 => "Decay Time",
+        76 => "Vibrato Rate",
+        77 => "Vibrato Depth",
+        78 => "Vibrato Delay",
+        79 => "Sound Controller 10",
+        80 => "General Purpose 5",
+        81 => "General Purpose 6",
+        82 => "General Purpose 7",
+        83 => "General Purpose 8",
+        84 => "Portamento Control",
+        88 => "High Resolution Velocity Prefix",
+        91 => "Reverb",
+        92 => "Tremolo",
+        93 => "Chorus",
+        94 => "Detune",
+        95 => "Phaser",
+        96 => "Data Increment",
+        97 => "Data Decrement",
+        98 => "NRPN LSB",
+        99 => "NRPN MSB",
+        100 => "RPN LSB",
+        101 => "RPN MSB",
+        120 => "All Sound Off",
+        121 => "Reset All Controllers",
+        122 => "Local Control",
+        123 => "All Notes Off",
+        124 => "Omni Off",
+        125 => "Omni On",
+        126 => "Mono On",
+        127 => "Poly On",
+        _ => return None,
+    };
+    Some(name)
+}
+
+/// Splits a message into a type name and up to two arguments, as used by the CSV and
+/// template formatters. Messages with a single argument leave the second one empty.
+pub(crate) fn message_fields(message: &Message) -> (&'static str, String, String) {
+    match *message {
+        Message::NoteOn(note, velocity) => ("note_on", note.to_string(), velocity.to_string()),
+        Message::NoteOff(note, velocity) => ("note_off", note.to_string(), velocity.to_string()),
+        Message::ControlChange(controller, value) => {
+            ("control_change", controller.to_string(), value.to_string())
+        }
+        Message::ControlChange14(controller, value) => (
+            "control_change_14bit",
+            controller.to_string(),
+            value.to_string(),
+        ),
+        Message::ProgramChange(program) => ("program_change", program.to_string(), String::new()),
+        Message::PitchBend(value) => ("pitch_bend", value.to_string(), String::new()),
+        Message::ChannelPressure(pressure) => {
+            ("channel_pressure", pressure.to_string(), String::new())
+        }
+        Message::PolyPressure(note, pressure) => {
+            ("poly_pressure", note.to_string(), pressure.to_string())
+        }
+        // the duration has no field of its own; `--format json` keeps it
+        Message::Note(note, velocity, _) => ("note", note.to_string(), velocity.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn note_names_put_middle_c_in_octave_four() {
+        assert_eq!(note_name(60), "C4");
+        assert_eq!(note_name(0), "C-1");
+        assert_eq!(note_name(61), "C#4");
+        assert_eq!(note_name(127), "G9");
+    }
+
+    #[test]
+    fn gm_instrument_names_are_zero_based() {
+        assert_eq!(gm_instrument_name(0), "Acoustic Grand Piano");
+        assert_eq!(gm_instrument_name(40), "Violin");
+        assert_eq!(gm_instrument_name(127), "Gunshot");
+    }
+
+    #[test]
+    fn cc_names_cover_assigned_controllers_only() {
+        assert_eq!(cc_name(1), Some("Modulation"));
+        assert_eq!(cc_name(7), Some("Volume"));
+        assert_eq!(cc_name(11), Some("Expression"));
+        assert_eq!(cc_name(64), Some("Sustain"));
+        assert_eq!(cc_name(3), None);
+        assert_eq!(cc_name(85), None);
+        assert_eq!(cc_name(102), None);
+    }
+}
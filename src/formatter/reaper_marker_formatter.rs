@@ -0,0 +1,20 @@
+/// Emits markers in Reaper's marker list import format:
+/// `R<index> <seconds> "<name>"`, with 1-based indices.
+#[derive(Default)]
+pub struct ReaperMarkerFormatter {}
+
+impl ReaperMarkerFormatter {
+    pub fn new() -> Self {
+        Self {}
+    }
+
+    pub fn format_marker(&self, index: usize, timestamp: f64, name: &str) -> String {
+        // R1 12.500 "Chorus"
+        format!(
+            "R{index} {timestamp:.3} \"{name}\"",
+            index = index,
+            timestamp = timestamp,
+            name = name.replace('"', "'")
+        )
+    }
+}
@@ -1,38 +1,390 @@
-use crate::formatter::MidiFormatter;
+use anyhow::{anyhow, bail, Result};
+
+use crate::formatter::{cc_name, gm_instrument_name, note_name, MidiFormatter};
 use crate::midi_event::{Message, MidiEvent};
-use std::time::Duration;
 
-pub struct StageTraxxFormatter {}
+pub(crate) const DEFAULT_TIME_PRECISION: u8 = 3;
+
+pub struct StageTraxxFormatter {
+    note_names: bool,
+    always_hours: bool,
+    precision: u8,
+    show_bars: bool,
+    group_by_bar: bool,
+    gm_names: bool,
+    cc_names: bool,
+    title: Option<String>,
+    artist: Option<String>,
+}
+
+impl Default for StageTraxxFormatter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl StageTraxxFormatter {
     pub fn new() -> Self {
-        Self {}
+        Self {
+            note_names: false,
+            always_hours: false,
+            precision: DEFAULT_TIME_PRECISION,
+            show_bars: false,
+            group_by_bar: false,
+            gm_names: false,
+            cc_names: false,
+            title: None,
+            artist: None,
+        }
+    }
+
+    /// Number of fractional digits in timestamps (0..=9, rounded rather than truncated).
+    pub fn with_time_precision(mut self, precision: u8) -> Self {
+        self.precision = precision.min(9);
+        self
+    }
+
+    /// Always emit `HH:MM:SS.mmm`, not just once the timestamp reaches an hour.
+    pub fn with_always_hours(mut self, always_hours: bool) -> Self {
+        self.always_hours = always_hours;
+        self
+    }
+
+    /// Append the musical position as a `; (bar:beat)` comment, when the event has one.
+    pub fn with_show_bars(mut self, show_bars: bool) -> Self {
+        self.show_bars = show_bars;
+        self
+    }
+
+    /// Separate bars with `; --- bar N ---` comment lines (see `bar_divider`).
+    pub fn with_group_by_bar(mut self, group_by_bar: bool) -> Self {
+        self.group_by_bar = group_by_bar;
+        self
+    }
+
+    /// The `; --- bar N ---` line to print before `event` when it lands in a different bar
+    /// than `last_bar`, the bar of the latest event before it that has a position. None
+    /// unless grouping by bar, or when the event has no position (e.g. a carried CC).
+    pub fn bar_divider(&self, last_bar: Option<u32>, event: &MidiEvent) -> Option<String> {
+        let bar = event.bar_beat.filter(|_| self.group_by_bar)?.bar;
+        (last_bar != Some(bar)).then(|| format!("; --- bar {} ---", bar))
+    }
+
+    /// Start the output with a `; title: ...` comment.
+    pub fn with_title(mut self, title: Option<String>) -> Self {
+        self.title = title;
+        self
+    }
+
+    /// Start the output with a `; artist: ...` comment (after the title, if any).
+    pub fn with_artist(mut self, artist: Option<String>) -> Self {
+        self.artist = artist;
+        self
+    }
+
+    /// Append the General MIDI instrument name to program changes as a comment.
+    pub fn with_gm_names(mut self, gm_names: bool) -> Self {
+        self.gm_names = gm_names;
+        self
+    }
+
+    /// Append the standard controller name to control changes as a comment. Undefined
+    /// controllers get no comment.
+    pub fn with_cc_names(mut self, cc_names: bool) -> Self {
+        self.cc_names = cc_names;
+        self
+    }
+
+    /// Render note numbers as pitch names (`NC4.100@1` instead of `N60.100@1`).
+    pub fn with_note_names(mut self, note_names: bool) -> Self {
+        self.note_names = note_names;
+        self
     }
 }
 
 impl MidiFormatter for StageTraxxFormatter {
+    fn preamble(&self) -> Option<String> {
+        // ; title: Song Name
+        // ; artist: Band
+        let lines: Vec<String> = [("title", &self.title), ("artist", &self.artist)]
+            .into_iter()
+            .filter_map(|(key, value)| value.as_ref().map(|v| format!("; {}: {}", key, v)))
+            .collect();
+        (!lines.is_empty()).then(|| lines.join("\n"))
+    }
+
     fn format(&self, event: &MidiEvent) -> String {
         // [midi@00:46.70: CC1.62@4]
-        let params: (&str, u8, u8) = match event.message {
-            Message::NoteOn(note, velocity) => ("N", note, velocity),
-            Message::NoteOff(note, _) => ("N", note, 0),
-            Message::ControlChange(num, val) => ("CC", num, val),
+        let mut line = format!(
+            "[midi@{timestamp}: {token}]",
+            timestamp = format_midi_time(event.timestamp, self.always_hours, self.precision),
+            token = message_token(event, self.note_names)
+        );
+        if let Some(pos) = event.bar_beat.filter(|_| self.show_bars) {
+            // [midi@00:46.70: CC1.62@4] ; (12:3)
+            line.push_str(&format!(" ; ({}:{})", pos.bar, pos.beat));
+        }
+        if let (true, Message::ProgramChange(program)) = (self.gm_names, &event.message) {
+            // [midi@00:00.000: PC0@1] ; Acoustic Grand Piano
+            line.push_str(&format!(" ; {}", gm_instrument_name(*program)));
+        }
+        let controller = match event.message {
+            Message::ControlChange(controller, _) | Message::ControlChange14(controller, _) => {
+                Some(controller)
+            }
+            _ => None,
         };
+        if let Some(name) = controller.filter(|_| self.cc_names).and_then(cc_name) {
+            // [midi@00:46.700: CC1.62@4] ; Modulation
+            line.push_str(&format!(" ; {}", name));
+        }
+        line
+    }
+}
+
+// CC1.62@4
+pub(crate) fn message_token(event: &MidiEvent, note_names: bool) -> String {
+    let note = |note: u8| {
+        if note_names {
+            note_name(note)
+        } else {
+            note.to_string()
+        }
+    };
+    let msg = match event.message {
+        Message::NoteOn(n, velocity) => format!("N{}.{}", note(n), velocity),
+        Message::NoteOff(n, _) => format!("N{}.0", note(n)),
+        Message::ControlChange(num, val) => format!("CC{}.{}", num, val),
+        // HCC7.12000: high resolution, so it can't be mistaken for a 7-bit CC7
+        Message::ControlChange14(num, val) => format!("HCC{}.{}", num, val),
+        Message::ProgramChange(program) => format!("PC{}", program),
+        Message::PitchBend(value) => format!("PB{}", value),
+        Message::ChannelPressure(pressure) => format!("AT{}", pressure),
+        Message::PolyPressure(n, pressure) => format!("PAT{}.{}", note(n), pressure),
+        // N60.100/1.250: a note with its duration in seconds
+        Message::Note(n, velocity, duration) => {
+            format!("N{}.{}/{:.3}", note(n), velocity, duration)
+        }
+    };
+    format!("{msg}@{channel}", msg = msg, channel = event.channel)
+}
+
+pub(crate) fn format_midi_time(seconds: f64, always_hours: bool, precision: u8) -> String {
+    // round in whole units of the last digit so carries propagate (0.9995 -> 1.00 at 2 digits).
+    // Snap to nanoseconds first: 0.9995 is stored as 0.99949999..., which would round down
+    // at 3 digits if scaled directly.
+    let scale = 10u64.pow(precision as u32);
+    let step = 10u64.pow(9 - precision.min(9) as u32);
+    let nanos = (seconds.max(0.0) * 1e9).round() as u64;
+    let units = (nanos + step / 2) / step;
+    let total_secs = units / scale;
+    let fractional = units % scale;
+
+    let hours = total_secs / 3600;
+    let minutes = total_secs / 60 % 60;
+    let seconds = total_secs % 60;
+    let time = if hours > 0 || always_hours {
+        format!("{:02}:{:02}:{:02}", hours, minutes, seconds)
+    } else {
+        format!("{:02}:{:02}", minutes, seconds)
+    };
+    if precision == 0 {
+        time
+    } else {
         format!(
-            "[midi@{timestamp}: {msg}{arg1}.{arg2}@{channel}]",
-            timestamp = format_midi_time(event.timestamp),
-            msg = params.0,
-            arg1 = params.1,
-            arg2 = params.2,
-            channel = event.channel
+            "{}.{:0width$}",
+            time,
+            fractional,
+            width = precision as usize
+        )
+    }
+}
+
+/// Parses a timestamp in the format StageTraxx lines use (`MM:SS.mmm` or `HH:MM:SS.mmm`,
+/// fraction optional) into seconds.
+pub fn parse_midi_time(time: &str) -> Result<f64> {
+    let invalid = || {
+        anyhow!(
+            "Invalid time '{}': expected MM:SS.mmm or HH:MM:SS.mmm",
+            time
         )
+    };
+    let parts: Vec<&str> = time.trim().split(':').collect();
+    let (hours, minutes, seconds) = match parts.as_slice() {
+        [m, s] => ("0", *m, *s),
+        [h, m, s] => (*h, *m, *s),
+        _ => return Err(invalid()),
+    };
+    let hours: u64 = hours.parse().map_err(|_| invalid())?;
+    let minutes: u64 = minutes.parse().map_err(|_| invalid())?;
+    // only digits and a dot, so "1e3" or "-5" don't slip through f64 parsing
+    if seconds.is_empty() || !seconds.chars().all(|c| c.is_ascii_digit() || c == '.') {
+        return Err(invalid());
+    }
+    let seconds: f64 = seconds.parse().map_err(|_| invalid())?;
+    if minutes >= 60 && parts.len() == 3 || seconds >= 60.0 {
+        bail!(
+            "Invalid time '{}': minutes and seconds must be below 60",
+            time
+        );
     }
+    Ok((hours * 3600 + minutes * 60) as f64 + seconds)
 }
 
-fn format_midi_time(seconds: f64) -> String {
-    let duration = Duration::from_secs_f64(seconds);
-    let minutes = duration.as_secs() / 60;
-    let seconds = duration.as_secs() % 60;
-    let fractional = duration.subsec_millis();
-    format!("{:02}:{:02}.{:03}", minutes, seconds, fractional)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi_event::BarBeat;
+
+    #[test]
+    fn hours_appear_once_a_timestamp_reaches_an_hour() {
+        assert_eq!(format_midi_time(3599.999, false, 3), "59:59.999");
+        assert_eq!(format_midi_time(3600.0, false, 3), "01:00:00.000");
+        assert_eq!(format_midi_time(3723.004, false, 3), "01:02:03.004");
+        assert_eq!(format_midi_time(5.0, true, 3), "00:00:05.000");
+    }
+
+    fn note_on(timestamp: f64) -> MidiEvent {
+        MidiEvent {
+            timestamp,
+            message: Message::NoteOn(60, 100),
+            channel: 1,
+            track: 0,
+            bar_beat: None,
+        }
+    }
+
+    #[test]
+    fn precision_rounds_and_carries() {
+        assert_eq!(format_midi_time(46.7, false, 2), "00:46.70");
+        assert_eq!(format_midi_time(0.9995, false, 2), "00:01.00");
+        assert_eq!(format_midi_time(0.9995, false, 3), "00:01.000");
+        assert_eq!(format_midi_time(59.96, false, 1), "01:00.0");
+        assert_eq!(format_midi_time(12.5, false, 0), "00:13");
+        assert_eq!(format_midi_time(1.000_000_001, false, 9), "00:01.000000001");
+    }
+
+    #[test]
+    fn formatter_uses_its_precision() {
+        let event = note_on(0.5);
+        let formatter = StageTraxxFormatter::new().with_time_precision(1);
+        assert_eq!(formatter.format(&event), "[midi@00:00.5: N60.100@1]");
+    }
+
+    #[test]
+    fn pitch_bend_token_is_signed() {
+        let mut event = note_on(0.0);
+        event.message = Message::PitchBend(-8192);
+        assert_eq!(message_token(&event, false), "PB-8192@1");
+    }
+
+    #[test]
+    fn gm_names_annotate_program_changes() {
+        let mut event = note_on(0.0);
+        event.message = Message::ProgramChange(24);
+        let formatter = StageTraxxFormatter::new().with_gm_names(true);
+        assert_eq!(
+            formatter.format(&event),
+            "[midi@00:00.000: PC24@1] ; Acoustic Guitar (nylon)"
+        );
+        assert_eq!(
+            StageTraxxFormatter::new().format(&event),
+            "[midi@00:00.000: PC24@1]"
+        );
+    }
+
+    #[test]
+    fn preamble_lists_title_and_artist() {
+        assert_eq!(StageTraxxFormatter::new().preamble(), None);
+        let formatter = StageTraxxFormatter::new()
+            .with_title(Some("Song".to_string()))
+            .with_artist(Some("Band".to_string()));
+        assert_eq!(
+            formatter.preamble().as_deref(),
+            Some("; title: Song\n; artist: Band")
+        );
+        let artist_only = StageTraxxFormatter::new().with_artist(Some("Band".to_string()));
+        assert_eq!(artist_only.preamble().as_deref(), Some("; artist: Band"));
+    }
+
+    #[test]
+    fn cc_names_annotate_named_controllers() {
+        let formatter = StageTraxxFormatter::new().with_cc_names(true);
+        let mut event = note_on(0.0);
+        event.message = Message::ControlChange(7, 100);
+        assert_eq!(
+            formatter.format(&event),
+            "[midi@00:00.000: CC7.100@1] ; Volume"
+        );
+        event.message = Message::ControlChange(11, 90);
+        assert_eq!(
+            formatter.format(&event),
+            "[midi@00:00.000: CC11.90@1] ; Expression"
+        );
+        // unassigned controllers keep just their number
+        event.message = Message::ControlChange(85, 100);
+        assert_eq!(formatter.format(&event), "[midi@00:00.000: CC85.100@1]");
+    }
+
+    fn event_at(bar: u32, beat: u32) -> MidiEvent {
+        let mut event = note_on(((bar - 1) * 4 + beat - 1) as f64 * 0.5);
+        event.bar_beat = Some(BarBeat { bar, beat, tick: 0 });
+        event
+    }
+
+    fn dividers(formatter: &StageTraxxFormatter, events: &[MidiEvent]) -> Vec<Option<String>> {
+        let mut last_bar = None;
+        events
+            .iter()
+            .map(|e| {
+                let divider = formatter.bar_divider(last_bar, e);
+                last_bar = e.bar_beat.map(|pos| pos.bar).or(last_bar);
+                divider
+            })
+            .collect()
+    }
+
+    #[test]
+    fn group_by_bar_divides_a_two_bar_file() {
+        let formatter = StageTraxxFormatter::new().with_group_by_bar(true);
+        let events = [
+            event_at(1, 1),
+            event_at(1, 3),
+            event_at(2, 1),
+            event_at(2, 4),
+        ];
+        assert_eq!(
+            dividers(&formatter, &events),
+            [
+                Some("; --- bar 1 ---".to_string()),
+                None,
+                Some("; --- bar 2 ---".to_string()),
+                None
+            ]
+        );
+        // dividers are separate lines, the events themselves are unchanged
+        assert_eq!(formatter.format(&events[2]), "[midi@00:02.000: N60.100@1]");
+    }
+
+    #[test]
+    fn group_by_bar_ignores_events_without_a_position() {
+        let formatter = StageTraxxFormatter::new().with_group_by_bar(true);
+        // e.g. a CC carried into a section that starts mid-bar
+        let events = [event_at(1, 1), note_on(1.0), event_at(1, 3), event_at(2, 1)];
+        assert_eq!(
+            dividers(&formatter, &events),
+            [
+                Some("; --- bar 1 ---".to_string()),
+                None,
+                None,
+                Some("; --- bar 2 ---".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn group_by_bar_is_off_by_default() {
+        let event = event_at(1, 1);
+        assert_eq!(StageTraxxFormatter::new().bar_divider(None, &event), None);
+    }
 }
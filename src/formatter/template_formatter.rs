@@ -0,0 +1,151 @@
+use anyhow::{bail, Result};
+
+use crate::formatter::stage_traxx_formatter::{format_midi_time, DEFAULT_TIME_PRECISION};
+use crate::formatter::{message_fields, MidiFormatter};
+use crate::midi_event::MidiEvent;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Placeholder {
+    Time,
+    Type,
+    Arg1,
+    Arg2,
+    Channel,
+}
+
+#[derive(Debug)]
+enum Segment {
+    Literal(String),
+    Placeholder(Placeholder),
+}
+
+/// Fills a user supplied template per event, e.g. `midi {channel} {type} {arg1} {arg2} @{time}`.
+/// Placeholders are `{time}`, `{type}`, `{arg1}`, `{arg2}` and `{channel}`; `{{` and `}}`
+/// are literal braces. The template is parsed once, so mistakes are reported up front.
+pub struct TemplateFormatter {
+    segments: Vec<Segment>,
+    always_hours: bool,
+    precision: u8,
+}
+
+impl TemplateFormatter {
+    pub fn new(template: &str) -> Result<Self> {
+        Ok(Self {
+            segments: parse_template(template)?,
+            always_hours: false,
+            precision: DEFAULT_TIME_PRECISION,
+        })
+    }
+
+    /// Number of fractional digits in `{time}` (0..=9).
+    pub fn with_time_precision(mut self, precision: u8) -> Self {
+        self.precision = precision.min(9);
+        self
+    }
+
+    /// Always include hours in `{time}`.
+    pub fn with_always_hours(mut self, always_hours: bool) -> Self {
+        self.always_hours = always_hours;
+        self
+    }
+}
+
+impl MidiFormatter for TemplateFormatter {
+    fn format(&self, event: &MidiEvent) -> String {
+        let (kind, arg1, arg2) = message_fields(&event.message);
+        let mut line = String::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(text) => line.push_str(text),
+                Segment::Placeholder(Placeholder::Time) => line.push_str(&format_midi_time(
+                    event.timestamp,
+                    self.always_hours,
+                    self.precision,
+                )),
+                Segment::Placeholder(Placeholder::Type) => line.push_str(kind),
+                Segment::Placeholder(Placeholder::Arg1) => line.push_str(&arg1),
+                Segment::Placeholder(Placeholder::Arg2) => line.push_str(&arg2),
+                Segment::Placeholder(Placeholder::Channel) => {
+                    line.push_str(&event.channel.to_string())
+                }
+            }
+        }
+        line
+    }
+}
+
+fn parse_template(template: &str) -> Result<Vec<Segment>> {
+    let mut segments = Vec::new();
+    let mut literal = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                literal.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                literal.push('}');
+            }
+            '{' => {
+                let mut name = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => name.push(c),
+                        None => bail!(
+                            "Unterminated placeholder '{{{}' in template (missing '}}')",
+                            name
+                        ),
+                    }
+                }
+                let placeholder = match name.as_str() {
+                    "time" => Placeholder::Time,
+                    "type" => Placeholder::Type,
+                    "arg1" => Placeholder::Arg1,
+                    "arg2" => Placeholder::Arg2,
+                    "channel" => Placeholder::Channel,
+                    _ => bail!(
+                        "Unknown template placeholder '{{{}}}' (expected time, type, arg1, arg2 or channel)",
+                        name
+                    ),
+                };
+                if !literal.is_empty() {
+                    segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                }
+                segments.push(Segment::Placeholder(placeholder));
+            }
+            '}' => bail!("Unmatched '}}' in template (use '}}}}' for a literal brace)"),
+            _ => literal.push(c),
+        }
+    }
+    if !literal.is_empty() {
+        segments.push(Segment::Literal(literal));
+    }
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unterminated_placeholder_is_an_error() {
+        let err = parse_template("midi {time").unwrap_err();
+        assert!(err.to_string().contains("missing '}'"), "{}", err);
+    }
+
+    #[test]
+    fn doubled_braces_are_literal() {
+        let segments = parse_template("{{{channel}}}").unwrap();
+        assert!(matches!(
+            segments.as_slice(),
+            [
+                Segment::Literal(open),
+                Segment::Placeholder(Placeholder::Channel),
+                Segment::Literal(close),
+            ] if open == "{" && close == "}"
+        ));
+    }
+}
@@ -0,0 +1,47 @@
+//! Converts MIDI files into StageTraxx 3 timecode cue lists.
+//!
+//! ```no_run
+//! use midi2stagetraxx::{Extractor, MidiFormatter, StageTraxxFormatter};
+//! use midi_file::MidiFile;
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let midi_file = MidiFile::load("song.mid")?;
+//! let events = Extractor::new(midi_file, None)?.run()?;
+//! let formatter = StageTraxxFormatter::new();
+//! for event in &events {
+//!     println!("{}", formatter.format(event));
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+mod diagnostics;
+mod extractor;
+pub mod formatter;
+mod logging;
+mod midi_event;
+mod reverse;
+mod scene_map;
+mod tempo_map;
+mod timing;
+mod transforms;
+
+pub use extractor::{Extractor, OverrideScope, VelocityCurve};
+pub use formatter::{MidiFormatter, StageTraxxFormatter};
+pub use midi_event::{BarBeat, Marker, Message, MidiEvent};
+pub use reverse::{parse_cue_list, write_type0};
+pub use scene_map::SceneMap;
+pub use tempo_map::TempoMap;
+
+/// The passes, checks and logging the `midi2stagetraxx` binary is built from. Not part of the
+/// library API: anything in here may change in any release.
+#[doc(hidden)]
+pub mod cli {
+    pub use crate::diagnostics::{event_stats, mixed_conductor_tracks, preflight, EventStats};
+    pub use crate::logging::{log, set_max_level, Level};
+    pub use crate::transforms::{
+        apply_offset, apply_window, carry_cc, dedupe_cc, dedupe_exact, exclusive_scenes,
+        hoist_setup_block, merge_cc14, notes_as_duration, off_at_section_ends, same_time,
+        skip_off_note_collisions, thin_cc, to_delta_times,
+    };
+}
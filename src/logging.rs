@@ -0,0 +1,63 @@
+//! Leveled diagnostics on stderr, so the CLI can silence or expand the chatter.
+//! Use the `warn!`, `info!` and `debug!` macros rather than `eprintln!`.
+
+use std::fmt;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    /// Nothing but errors
+    Quiet = 0,
+    /// Problems with the input that change the output
+    Warn = 1,
+    /// One-off facts about the run (the default)
+    Info = 2,
+    /// Per-event details: tempo changes, meta events, unhandled messages
+    Debug = 3,
+}
+
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+/// Messages above `level` are dropped.
+pub fn set_max_level(level: Level) {
+    MAX_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= MAX_LEVEL.load(Ordering::Relaxed)
+}
+
+#[doc(hidden)]
+pub fn log(level: Level, args: fmt::Arguments) {
+    if !enabled(level) {
+        return;
+    }
+    match level {
+        Level::Warn => eprintln!("WARNING: {}", args),
+        _ => eprintln!("{}", args),
+    }
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        $crate::cli::log($crate::cli::Level::Warn, format_args!($($arg)*))
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => {
+        $crate::cli::log($crate::cli::Level::Info, format_args!($($arg)*))
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        $crate::cli::log($crate::cli::Level::Debug, format_args!($($arg)*))
+    };
+}
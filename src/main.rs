@@ -1,49 +1,1523 @@
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use clap::arg;
-use clap::Parser;
+use clap::{CommandFactory, Parser, ValueEnum};
 
-use formatter::MidiFormatter;
+mod config;
+
+use config::{config_args, parse_config};
+use midi2stagetraxx::cli::{self, Level};
+use midi2stagetraxx::formatter;
+use midi2stagetraxx::{debug, info, warn};
+use midi2stagetraxx::{
+    parse_cue_list, write_type0, Extractor, Marker, Message, MidiEvent, MidiFormatter,
+    OverrideScope, SceneMap, TempoMap, VelocityCurve,
+};
 use midi_file::MidiFile;
-mod extractor;
-use extractor::Extractor;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum OutputFormat {
+    /// One `[midi@MM:SS.mmm: ...]` line per event
+    Stagetraxx,
+    /// One line per timestamp listing every message at that time
+    Compact,
+    /// One JSON object per event (JSON Lines)
+    Json,
+    /// CSV rows (`timestamp_seconds,message_type,arg1,arg2,channel`) with a header row
+    Csv,
+    /// Reaper marker list built from Marker meta events
+    ReaperMarkers,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum SameTimeOrder {
+    /// Note offs before note ons, so the old scene turns off before the new one turns on
+    OffFirst,
+    /// Note ons before note offs
+    OnFirst,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum OverrideScopeArg {
+    /// Notes only
+    Notes,
+    /// Control changes only
+    Cc,
+    /// Every message type
+    All,
+}
 
-mod formatter;
-mod midi_event;
+impl From<OverrideScopeArg> for OverrideScope {
+    fn from(scope: OverrideScopeArg) -> Self {
+        match scope {
+            OverrideScopeArg::Notes => OverrideScope::Notes,
+            OverrideScopeArg::Cc => OverrideScope::ControlChanges,
+            OverrideScopeArg::All => OverrideScope::All,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum VelocityCurveArg {
+    /// Velocities unchanged
+    Linear,
+    /// Gamma 2: soft hits stay soft, the top of the range opens up
+    Exp,
+    /// Gamma 1/2: soft hits ramp up faster
+    Log,
+    /// 127 - velocity
+    Invert,
+}
+
+impl From<VelocityCurveArg> for VelocityCurve {
+    fn from(curve: VelocityCurveArg) -> Self {
+        match curve {
+            VelocityCurveArg::Linear => VelocityCurve::Linear,
+            VelocityCurveArg::Exp => VelocityCurve::Exp,
+            VelocityCurveArg::Log => VelocityCurve::Log,
+            VelocityCurveArg::Invert => VelocityCurve::Invert,
+        }
+    }
+}
 
 #[derive(Parser, Debug)]
 #[command(version, about)]
 struct Args {
-    #[arg(short, long)]
-    midi_file: String,
+    #[arg(
+        short,
+        long = "midi-file",
+        required = true,
+        num_args = 1..,
+        help = "MIDI file(s) to convert, or `-` to read one from standard input. With several files, each is written to a sibling .txt file (or into --output-dir)"
+    )]
+    midi_files: Vec<String>,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Write the converted output to this file instead of stdout"
+    )]
+    output: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "LINES",
+        default_value_t = 4096,
+        help = "Flush the output every this many lines, so a long conversion shows up (and survives an interruption) as it goes. 0 only flushes at the end"
+    )]
+    flush_interval: usize,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Read default options from a TOML file whose keys mirror the long flags (e.g. `transpose = -12`, `format = \"json\"`). Only top-level `key = value` lines are supported, with strings, numbers, booleans or one-line arrays. Flags given on the command line take precedence, also over config options they conflict with"
+    )]
+    config: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "DIR",
+        conflicts_with = "output",
+        help = "Write each converted file into this directory as <name>.txt"
+    )]
+    output_dir: Option<PathBuf>,
+
+    #[arg(
+        short,
+        long,
+        help = "Also log per-event diagnostics (tempo changes, meta events, unhandled messages) to stderr"
+    )]
+    verbose: bool,
+
+    #[arg(
+        short,
+        long,
+        conflicts_with = "verbose",
+        help = "Only log errors to stderr"
+    )]
+    quiet: bool,
 
     #[arg(
         short,
         long,
-        help = "Override the MIDI channel for all notes and CC changes"
+        value_name = "CHANNEL",
+        value_parser = clap::value_parser!(u8).range(1..=16),
+        help = "Override the MIDI channel for all notes and CC changes (1-based, 1..=16)"
     )]
     override_midi_channel: Option<u8>,
 
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = OverrideScopeArg::All,
+        help = "Which messages --override-midi-channel applies to"
+    )]
+    override_scope: OverrideScopeArg,
+
+    #[arg(
+        long,
+        value_name = "CHANNEL",
+        value_parser = clap::value_parser!(u8).range(1..=16),
+        help = "Only extract events on this (1-based) channel, matched before any override is applied"
+    )]
+    only_channel: Option<u8>,
+
+    #[arg(
+        long = "track",
+        value_name = "INDEX",
+        help = "Only extract events from this track index (repeatable; defaults to all tracks). Tempo changes are read from every track, except in Type 2 files where each track keeps its own"
+    )]
+    tracks: Vec<usize>,
+
+    #[arg(
+        long,
+        value_name = "MS",
+        default_value_t = 0,
+        help = "In a Type 2 file, where each track is its own sequence played back to back, leave this much silence between sequences"
+    )]
+    track_gap_ms: u32,
+
+    #[arg(
+        long,
+        value_name = "CHANNEL",
+        value_parser = clap::value_parser!(u8).range(1..=16),
+        help = "Override the MIDI channel for CC changes only (1-based; takes precedence over --override-midi-channel for CC)"
+    )]
+    override_cc_channel: Option<u8>,
+
+    #[arg(
+        long,
+        value_name = "FROM=TO,...",
+        help = "Move messages from one (1-based) channel to another, e.g. `1=4,10=5`; unlisted channels are unchanged"
+    )]
+    remap: Option<String>,
+
+    #[arg(
+        long,
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u8).range(0..=1),
+        help = "Number the output channels from 0 or 1 (StageTraxx uses 1); override and remap channels are used as given"
+    )]
+    channel_base: u8,
+
     #[arg(
         long,
         help = "Skip off notes that arrive at the same time as an ON note (this can help with timing issues when controlling mutually exclusive scenes with lights)"
     )]
     skip_off_note_collisions: bool,
+
+    #[arg(
+        long,
+        value_name = "CHANNEL",
+        value_parser = clap::value_parser!(u8).range(1..=16),
+        help = "Treat this output channel as mutually exclusive scenes: each NoteOn there first turns off the previous scene note, even if the file never did"
+    )]
+    scene_channel: Option<u8>,
+
+    #[arg(
+        long,
+        help = "Turn off every held note just before each marker and at the end, so each section ends cleanly"
+    )]
+    off_at_section_end: bool,
+
+    #[arg(
+        long,
+        help = "At each marker, re-send the latest value of every controller set before it, so each section starts with the right controller state"
+    )]
+    carry_cc: bool,
+
+    #[arg(
+        long,
+        help = "Show each event's time as the delta since the previous event instead of an absolute timestamp"
+    )]
+    delta_time: bool,
+
+    #[arg(
+        long,
+        requires = "delta_time",
+        help = "With --delta-time, start the first event at 00:00 rather than its absolute time"
+    )]
+    delta_from_zero: bool,
+
+    #[arg(
+        long,
+        default_value_t = 0,
+        help = "Treat NoteOn events with a velocity at or below this value as NoteOff"
+    )]
+    off_velocity_threshold: u8,
+
+    #[arg(
+        long,
+        help = "Only print the number of events that would be emitted, skipping formatting entirely"
+    )]
+    count_only: bool,
+
+    #[arg(
+        long,
+        help = "Append the index of the source track to each line as a comment (useful for debugging multi-track files)"
+    )]
+    annotate_track: bool,
+
+    #[arg(
+        long,
+        help = "Fail if the file has no tempo set before the first MIDI event instead of assuming 120 BPM"
+    )]
+    require_tempo: bool,
+
+    #[arg(
+        long,
+        help = "If the first tempo change comes after the first MIDI event, apply it from the start instead of assuming 120 BPM until then"
+    )]
+    assume_first_tempo: bool,
+
+    #[arg(
+        long,
+        help = "CSV file of `note,name` or `note,channel,name` lines; matching NoteOns get a `; scene: <name>` comment"
+    )]
+    scene_map: Option<String>,
+
+    #[arg(
+        long,
+        help = "Warn when tempo/time signature events share a track with many notes, which can produce confusing merges"
+    )]
+    warn_mixed_conductor: bool,
+
+    #[arg(
+        long,
+        help = "Round NoteOn velocities to the nearest multiple of this step (e.g. 16 for 8 intensity levels)"
+    )]
+    velocity_step: Option<u8>,
+
+    #[arg(
+        long,
+        value_name = "MIN:MAX",
+        help = "Linearly map CC values onto this output range (e.g. 20:100); MIN > MAX inverts"
+    )]
+    cc_scale: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "COARSE:FINE",
+        help = "Merge a coarse CC followed by its fine CC on the same channel into one 14-bit `HCC<coarse>.<value>` event (e.g. 7:39); with --reverse, HCC events on COARSE write their fine byte to FINE"
+    )]
+    cc14: Option<String>,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = VelocityCurveArg::Linear,
+        help = "Reshape NoteOn velocities (applied before --velocity-step; note-ons never drop to 0)"
+    )]
+    velocity_curve: VelocityCurveArg,
+
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "CSV tempo map (`tick,bpm` lines, or `seconds,bpm` with a `seconds,bpm` header) replacing the file's tempo events"
+    )]
+    tempo_map_in: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "BPM",
+        help = "Ignore all tempo information (the file's tempo events and --tempo-map-in) and time everything at this fixed BPM"
+    )]
+    force_bpm: Option<f64>,
+
+    #[arg(
+        long,
+        value_name = "SEMITONES",
+        default_value_t = 0,
+        allow_negative_numbers = true,
+        help = "Shift note numbers by this many semitones (clamped to 0..=127); CC and other messages are unaffected"
+    )]
+    transpose: i8,
+
+    #[arg(
+        long,
+        value_name = "NOTE",
+        default_value_t = 0,
+        value_parser = clap::value_parser!(u8).range(0..=127),
+        help = "Drop notes below this note number (checked after --transpose)"
+    )]
+    note_min: u8,
+
+    #[arg(
+        long,
+        value_name = "NOTE",
+        default_value_t = 127,
+        value_parser = clap::value_parser!(u8).range(0..=127),
+        help = "Drop notes above this note number (checked after --transpose)"
+    )]
+    note_max: u8,
+
+    #[arg(
+        long,
+        help = "Extract channel aftertouch (`AT<pressure>`) and polyphonic aftertouch (`PAT<note>.<pressure>`) instead of skipping them"
+    )]
+    include_aftertouch: bool,
+
+    #[arg(long, help = "Start StageTraxx output with a `; title: ...` comment")]
+    title: Option<String>,
+
+    #[arg(long, help = "Start StageTraxx output with a `; artist: ...` comment")]
+    artist: Option<String>,
+
+    #[arg(
+        long,
+        help = "Show note numbers as pitch names (MIDI 60 = C4) in StageTraxx and compact output"
+    )]
+    note_names: bool,
+
+    #[arg(
+        long,
+        help = "Append each event's bar:beat position (from the file's time signatures) as a comment in StageTraxx output"
+    )]
+    show_bars: bool,
+
+    #[arg(
+        long,
+        help = "Separate bars with a `; --- bar N ---` comment line in StageTraxx output (timestamps are unchanged)"
+    )]
+    group_by_bar: bool,
+
+    #[arg(
+        long,
+        help = "Append the General MIDI instrument name to program changes as a comment in StageTraxx output"
+    )]
+    gm_names: bool,
+
+    #[arg(
+        long,
+        help = "Append the standard controller name (e.g. Modulation, Sustain) to control changes as a comment in StageTraxx output"
+    )]
+    cc_names: bool,
+
+    #[arg(
+        long,
+        help = "Always include hours in timestamps (HH:MM:SS.mmm); otherwise they appear once a timestamp reaches an hour"
+    )]
+    always_hours: bool,
+
+    #[arg(
+        long,
+        default_value_t = 3,
+        value_parser = clap::value_parser!(u8).range(0..=9),
+        help = "Number of fractional digits in timestamps (rounded)"
+    )]
+    time_precision: u8,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = OutputFormat::Stagetraxx,
+        help = "Output format"
+    )]
+    format: OutputFormat,
+
+    #[arg(
+        long,
+        value_name = "FMT",
+        conflicts_with = "format",
+        help = "Format each event with a template instead of --format, e.g. `midi {channel} {type} {arg1} {arg2} @{time}`"
+    )]
+    template: Option<String>,
+
+    #[arg(
+        long,
+        help = "Write each output channel to its own file, named after the output with `.chN` before the extension (e.g. song.ch4.txt); needs --output or --output-dir"
+    )]
+    split_by_channel: bool,
+
+    #[arg(
+        long = "channel-format",
+        value_name = "CH:FORMAT",
+        requires = "split_by_channel",
+        conflicts_with = "template",
+        help = "With --split-by-channel, write this output channel in another format than --format, e.g. `10:json` (repeatable). Valid per channel: stagetraxx, compact, json and csv"
+    )]
+    channel_formats: Vec<String>,
+
+    #[arg(
+        long,
+        default_value = " ",
+        help = "Separator between message tokens for --format compact"
+    )]
+    compact_delimiter: String,
+
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Only emit the first N events (after all filters), followed by a comment with the total"
+    )]
+    preview: Option<usize>,
+
+    #[arg(
+        long,
+        help = "Print a summary of the emitted events (counts per type, channels, tempo changes, final timestamp) to stderr"
+    )]
+    stats: bool,
+
+    #[arg(
+        long,
+        help = "Exit with an error if no events remain after filtering (useful to catch misconfigured filters in batch jobs)"
+    )]
+    fail_on_empty: bool,
+
+    #[arg(
+        long,
+        help = "With --format reaper-markers, also emit a marker for every NoteOn"
+    )]
+    reaper_note_markers: bool,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["override_midi_channel", "override_cc_channel"],
+        help = "Put each source track on its own channel (track index + 1, clamped to 16)"
+    )]
+    channel_per_track: bool,
+
+    #[arg(
+        long,
+        requires = "output",
+        help = "Read a StageTraxx cue list from --midi-file and write it to --output as a Type-0 MIDI file"
+    )]
+    reverse: bool,
+
+    #[arg(
+        long,
+        default_value_t = 480,
+        value_parser = clap::value_parser!(u16).range(1..=0x7FFF),
+        requires = "reverse",
+        help = "Pulses per quarter note of the MIDI file written by --reverse (1-32767)"
+    )]
+    reverse_ppq: u16,
+
+    #[arg(
+        long,
+        default_value_t = 120.0,
+        requires = "reverse",
+        help = "Fixed tempo (BPM) of the MIDI file written by --reverse"
+    )]
+    reverse_bpm: f64,
+
+    #[arg(
+        long,
+        help = "Check the file for likely problems (SMPTE division, missing tempo, low PPQ, mixed conductor track, stuck notes) and print a report instead of converting"
+    )]
+    preflight: bool,
+
+    #[arg(
+        long,
+        requires = "preflight",
+        help = "Exit with an error if --preflight finds any issue"
+    )]
+    strict: bool,
+
+    #[arg(
+        long,
+        value_name = "MS",
+        allow_negative_numbers = true,
+        help = "Shift every event by this many milliseconds: positive fires cues later, negative earlier (clamped at 00:00)"
+    )]
+    offset_ms: Option<i64>,
+
+    #[arg(
+        long,
+        value_name = "MM:SS.mmm",
+        help = "Drop events before this time (HH:MM:SS.mmm also accepted)"
+    )]
+    start: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "MM:SS.mmm",
+        help = "Drop events after this time (HH:MM:SS.mmm also accepted)"
+    )]
+    end: Option<String>,
+
+    #[arg(
+        long,
+        requires = "start",
+        help = "Re-zero timestamps so --start becomes 00:00"
+    )]
+    rebase: bool,
+
+    #[arg(
+        long,
+        value_name = "SUBDIVISION",
+        help = "Snap events to the nearest grid point of this note length (e.g. 1/16), following the tempo map"
+    )]
+    quantize: Option<String>,
+
+    #[arg(
+        long,
+        help = "Start the timeline at the file's SMPTE offset (for video-synced shows) instead of 00:00"
+    )]
+    apply_smpte_offset: bool,
+
+    #[arg(
+        long,
+        help = "Don't add NoteOffs at the end for notes the file leaves held"
+    )]
+    no_flush: bool,
+
+    #[arg(
+        long,
+        help = "Emit each NoteOn/NoteOff pair as a single note with its duration (`N60.100/1.250@1`)"
+    )]
+    notes_as_duration: bool,
+
+    #[arg(
+        long,
+        help = "Drop control changes that repeat the previous value for the same controller and channel"
+    )]
+    dedupe_cc: bool,
+
+    #[arg(
+        long,
+        value_name = "MS",
+        help = "Drop CCs that follow the previous kept value of the same controller and channel by less than this many milliseconds (the last value of each sweep is kept)"
+    )]
+    min_gap_ms: Option<f64>,
+
+    #[arg(
+        long,
+        help = "Drop an event when the one right before it has the same channel and message at the same time (see --time-epsilon)"
+    )]
+    dedupe_exact: bool,
+
+    #[arg(
+        long,
+        help = "Move bank select, program change and CC events that occur before the first note into a setup block at time 0"
+    )]
+    setup_block: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = SameTimeOrder::OffFirst,
+        help = "Order of note offs and note ons that share a timestamp"
+    )]
+    same_time_order: SameTimeOrder,
+
+    #[arg(
+        long,
+        value_name = "MS",
+        default_value_t = 0.0,
+        help = "Events closer together than this many milliseconds count as simultaneous for collision checks, grouping and --dedupe-exact (default 0: exact match)"
+    )]
+    time_epsilon: f64,
+}
+
+/// Settings parsed from the arguments once and shared by every converted file.
+struct Options {
+    scene_map: Option<SceneMap>,
+    tempo_map: Option<TempoMap>,
+    template: Option<formatter::TemplateFormatter>,
+    start: Option<f64>,
+    end: Option<f64>,
+    cc_scale: Option<(u8, u8)>,
+    cc14: Option<(u8, u8)>,
+    quantize: Option<(u32, u32)>,
+    channel_remap: HashMap<u8, u8>,
+    channel_formats: HashMap<u8, OutputFormat>,
 }
 
 fn main() -> Result<()> {
-    let args = Args::parse();
-    let midi_file = MidiFile::load(args.midi_file).context("load midi file")?;
-    let mut extractor = Extractor::new(midi_file, args.override_midi_channel)?;
-    let events = extractor.run()?;
-    let formatter = formatter::StageTraxxFormatter::new();
+    let args = Args::parse_from(args_with_config()?);
+    cli::set_max_level(match (args.quiet, args.verbose) {
+        (true, _) => Level::Quiet,
+        (_, true) => Level::Debug,
+        _ => Level::Info,
+    });
+    if let Some(config) = &args.config {
+        debug!("Loaded default options from {}", config);
+    }
+    let options = parse_options(&args)?;
+
+    if args.reverse {
+        return run_reverse(&args);
+    }
 
-    for (event, next) in events.iter().zip(events.iter().skip(1)) {
-        if event.timestamp == next.timestamp && args.skip_off_note_collisions {
-            // drop the note off event to avoid conflicts
-            continue;
+    let batch = args.midi_files.len() > 1 || args.output_dir.is_some();
+    if !batch {
+        return convert(
+            &args,
+            &options,
+            &args.midi_files[0],
+            args.output.as_deref().map(Path::new),
+        );
+    }
+    if args.output.is_some() {
+        bail!("--output takes a single file; use --output-dir when converting several");
+    }
+    if args.midi_files.iter().any(|f| f == "-") {
+        bail!("stdin (`-`) can only be converted on its own");
+    }
+    if let Some(dir) = &args.output_dir {
+        fs::create_dir_all(dir).with_context(|| format!("create {}", dir.display()))?;
+    }
+
+    let outputs: Vec<PathBuf> = args
+        .midi_files
+        .iter()
+        .map(|midi_file| batch_output_path(midi_file, args.output_dir.as_deref()))
+        .collect();
+    check_output_collisions(&args.midi_files, &outputs)?;
+
+    // keep going after a failure so one bad song doesn't hold up the rest; failures are
+    // reported once, in the summary
+    let mut failed = 0;
+    let mut results = Vec::new();
+    for (midi_file, output) in args.midi_files.iter().zip(outputs) {
+        let result = convert(&args, &options, midi_file, Some(&output));
+        if result.is_err() {
+            failed += 1;
         }
-        println!("{}", formatter.format(event));
+        results.push((midi_file, output, result));
     }
 
+    eprintln!(
+        "Converted {} of {} files:",
+        results.len() - failed,
+        results.len()
+    );
+    for (midi_file, output, result) in &results {
+        match result {
+            Ok(()) => eprintln!("  ok     {} -> {}", midi_file, output.display()),
+            Err(err) => eprintln!("  FAILED {}: {:#}", midi_file, err),
+        }
+    }
+    if failed > 0 {
+        bail!("{} of {} files failed", failed, results.len());
+    }
     Ok(())
 }
+
+/// The command line with the options from `--config` (if given) spliced in right after the
+/// program name. Options the command line sets itself are left out of the config's share.
+fn args_with_config() -> Result<Vec<String>> {
+    let mut args: Vec<String> = env::args().collect();
+    let config = args
+        .iter()
+        .enumerate()
+        .find_map(|(i, arg)| match arg.as_str() {
+            "--config" => args.get(i + 1).cloned(),
+            _ => arg.strip_prefix("--config=").map(str::to_string),
+        });
+    if let Some(path) = config {
+        let contents =
+            fs::read_to_string(&path).with_context(|| format!("read config {}", path))?;
+        let config = parse_config(&contents).with_context(|| format!("parse config {}", path))?;
+        let config_args = config_args(&config, &Args::command(), &args[1..])
+            .with_context(|| format!("config {}", path))?;
+        args.splice(1..1, config_args);
+    }
+    Ok(args)
+}
+
+/// Converts a StageTraxx cue list back into a MIDI file.
+fn run_reverse(args: &Args) -> Result<()> {
+    let [input] = args.midi_files.as_slice() else {
+        bail!("--reverse converts one cue list at a time");
+    };
+    let Some(output) = &args.output else {
+        bail!("--reverse needs --output");
+    };
+    if !(args.reverse_bpm.is_finite() && args.reverse_bpm > 0.0) {
+        bail!("--reverse-bpm must be a positive number");
+    }
+    let cc14 = parse_cc14(args)?;
+
+    let contents = if input == "-" {
+        io::read_to_string(io::stdin()).context("read cue list from stdin")?
+    } else {
+        fs::read_to_string(input).with_context(|| format!("read cue list {}", input))?
+    };
+    let events = parse_cue_list(&contents).with_context(|| format!("parse cue list {}", input))?;
+
+    let mut out = BufWriter::new(
+        File::create(output).with_context(|| format!("create output file {}", output))?,
+    );
+    write_type0(&events, args.reverse_ppq, args.reverse_bpm, cc14, &mut out)
+        .and_then(|_| out.flush())
+        .context("write midi file")?;
+    info!("Wrote {} events to {}", events.len(), output);
+    Ok(())
+}
+
+fn parse_cc14(args: &Args) -> Result<Option<(u8, u8)>> {
+    let cc14 = args
+        .cc14
+        .as_deref()
+        .map(|s| parse_value_pair(s, 127))
+        .transpose()
+        .context("parse --cc14")?;
+    if let Some((coarse, fine)) = cc14 {
+        if coarse == fine {
+            bail!("--cc14 needs two different controllers");
+        }
+    }
+    Ok(cc14)
+}
+
+fn parse_options(args: &Args) -> Result<Options> {
+    if let Some(bpm) = args
+        .force_bpm
+        .filter(|bpm| !(bpm.is_finite() && *bpm > 0.0))
+    {
+        bail!("--force-bpm must be a positive number, got {}", bpm);
+    }
+    // Reaper markers are placed on the project timeline, so they are always absolute
+    if args.delta_time && args.format == OutputFormat::ReaperMarkers {
+        bail!("--delta-time can't be used with --format reaper-markers");
+    }
+    if !(args.time_epsilon.is_finite() && args.time_epsilon >= 0.0) {
+        bail!(
+            "--time-epsilon must be zero or more milliseconds, got {}",
+            args.time_epsilon
+        );
+    }
+    let scene_map = args.scene_map.as_deref().map(SceneMap::load).transpose()?;
+    let tempo_map = args
+        .tempo_map_in
+        .as_deref()
+        .map(TempoMap::load)
+        .transpose()?;
+    let template = args
+        .template
+        .as_deref()
+        .map(|t| {
+            formatter::TemplateFormatter::new(t).map(|f| {
+                f.with_always_hours(args.always_hours)
+                    .with_time_precision(args.time_precision)
+            })
+        })
+        .transpose()
+        .context("parse --template")?;
+    let start = args
+        .start
+        .as_deref()
+        .map(formatter::parse_midi_time)
+        .transpose()
+        .context("parse --start")?;
+    let end = args
+        .end
+        .as_deref()
+        .map(formatter::parse_midi_time)
+        .transpose()
+        .context("parse --end")?;
+    let cc_scale = args
+        .cc_scale
+        .as_deref()
+        .map(|s| parse_value_pair(s, 127))
+        .transpose()
+        .context("parse --cc-scale")?;
+    let cc14 = parse_cc14(args)?;
+    let quantize = args
+        .quantize
+        .as_deref()
+        .map(parse_subdivision)
+        .transpose()
+        .context("parse --quantize")?;
+    let channel_remap = args
+        .remap
+        .as_deref()
+        .map(parse_channel_remap)
+        .transpose()
+        .context("parse --remap")?
+        .unwrap_or_default();
+    if args.note_min > args.note_max {
+        bail!("--note-min must not be above --note-max");
+    }
+    if let (Some(start), Some(end)) = (start, end) {
+        if start > end {
+            bail!("--start must not be after --end");
+        }
+    }
+    if args.split_by_channel {
+        if args.output.is_none() && args.output_dir.is_none() {
+            bail!("--split-by-channel needs --output or --output-dir");
+        }
+        if args.format == OutputFormat::ReaperMarkers {
+            bail!("--split-by-channel can't be used with --format reaper-markers");
+        }
+    }
+    let mut channel_formats = HashMap::new();
+    for value in &args.channel_formats {
+        let (channel, format) = parse_channel_format(value).context("parse --channel-format")?;
+        channel_formats.insert(channel, format);
+    }
+
+    Ok(Options {
+        scene_map,
+        tempo_map,
+        template,
+        start,
+        end,
+        cc_scale,
+        cc14,
+        quantize,
+        channel_remap,
+        channel_formats,
+    })
+}
+
+/// `song.mid` becomes `song.txt`, next to the source or inside `output_dir`.
+fn batch_output_path(midi_file: &str, output_dir: Option<&Path>) -> PathBuf {
+    let sibling = Path::new(midi_file).with_extension("txt");
+    match (output_dir, sibling.file_name()) {
+        (Some(dir), Some(name)) => dir.join(name),
+        _ => sibling,
+    }
+}
+
+/// Fails when two inputs would be written to the same output file (e.g. `a/song.mid` and
+/// `b/song.mid` with `--output-dir`), before anything is converted.
+fn check_output_collisions(midi_files: &[String], outputs: &[PathBuf]) -> Result<()> {
+    let mut seen: HashMap<&Path, &str> = HashMap::new();
+    for (midi_file, output) in midi_files.iter().zip(outputs) {
+        if let Some(first) = seen.insert(output, midi_file) {
+            bail!(
+                "{} and {} would both be written to {}",
+                first,
+                midi_file,
+                output.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Converts a single MIDI file, writing to `output` or stdout.
+fn convert(args: &Args, options: &Options, midi_path: &str, output: Option<&Path>) -> Result<()> {
+    let midi_file = load_midi_file(midi_path)?;
+    if args.preflight {
+        let issues = cli::preflight(&midi_file);
+        if issues.is_empty() {
+            println!("Preflight: no issues found");
+        } else {
+            println!("Preflight: {} issue(s) found", issues.len());
+            for issue in &issues {
+                println!("  - {}", issue);
+            }
+        }
+        if args.strict && !issues.is_empty() {
+            bail!("Preflight failed");
+        }
+        return Ok(());
+    }
+
+    if args.warn_mixed_conductor {
+        for mixed in cli::mixed_conductor_tracks(&midi_file) {
+            warn!(
+                "track {} mixes {} tempo/time signature events with {} note events; consider moving conductor data to its own track",
+                mixed.track, mixed.conductor_events, mixed.note_events
+            );
+        }
+    }
+    let mut extractor = Extractor::new(midi_file, args.override_midi_channel)?
+        .with_override_cc_channel(args.override_cc_channel)
+        .with_override_scope(args.override_scope.into())
+        .with_channel_per_track(args.channel_per_track)
+        .with_channel_base(args.channel_base)
+        .with_only_channel(args.only_channel)
+        .with_tracks(args.tracks.clone())
+        .with_track_gap_ms(args.track_gap_ms)
+        .with_off_velocity_threshold(args.off_velocity_threshold)
+        .with_require_tempo(args.require_tempo)
+        .with_assume_first_tempo(args.assume_first_tempo)
+        .with_velocity_curve(args.velocity_curve.into())
+        .with_velocity_step(args.velocity_step)
+        .with_transpose(args.transpose)
+        .with_note_range(args.note_min, args.note_max)
+        .with_include_aftertouch(args.include_aftertouch)
+        .with_note_off_first(args.same_time_order == SameTimeOrder::OffFirst)
+        .with_cc_scale(options.cc_scale)
+        .with_channel_remap(options.channel_remap.clone())
+        .with_quantize(options.quantize)
+        .with_apply_smpte_offset(args.apply_smpte_offset)
+        .with_flush_held_notes(!args.no_flush)
+        .with_tempo_map(options.tempo_map.as_ref())?
+        .with_force_bpm(args.force_bpm);
+    let mut events = extractor.run()?;
+    // sections start at markers
+    let mut boundaries: Vec<f64> = extractor.markers().iter().map(|m| m.timestamp).collect();
+    boundaries.sort_by(f64::total_cmp);
+    if args.off_at_section_end {
+        cli::off_at_section_ends(&mut events, &boundaries);
+    }
+    // pair notes before anything drops events, so a window or collision skip can't orphan them
+    if args.notes_as_duration {
+        cli::notes_as_duration(&mut events);
+    }
+    if let Some((coarse, fine)) = options.cc14 {
+        cli::merge_cc14(&mut events, coarse, fine);
+    }
+    // after the merge, so a 14-bit controller is carried as one value
+    if args.carry_cc {
+        cli::carry_cc(&mut events, &boundaries);
+    }
+    if options.start.is_some() || options.end.is_some() {
+        cli::apply_window(&mut events, options.start, options.end, args.rebase);
+    }
+    if args.dedupe_cc {
+        cli::dedupe_cc(&mut events);
+    }
+    if args.dedupe_exact {
+        cli::dedupe_exact(&mut events, args.time_epsilon);
+    }
+    if let Some(min_gap_ms) = args.min_gap_ms {
+        cli::thin_cc(&mut events, min_gap_ms);
+    }
+    if let Some(offset_ms) = args.offset_ms {
+        cli::apply_offset(&mut events, offset_ms);
+    }
+    let setup_len = if args.setup_block {
+        cli::hoist_setup_block(&mut events)
+    } else {
+        0
+    };
+
+    if args.skip_off_note_collisions {
+        cli::skip_off_note_collisions(&mut events, args.time_epsilon);
+    }
+    // after the collision skip, which would otherwise drop the offs injected here
+    if let Some(channel) = args.scene_channel {
+        cli::exclusive_scenes(&mut events, channel);
+    }
+    if args.stats {
+        print_stats(&cli::event_stats(
+            &events,
+            extractor.tempo_change_count(),
+            extractor.duration(),
+        ));
+    }
+    if args.fail_on_empty && events.is_empty() {
+        bail!("No events to emit after filtering");
+    }
+    if !args.split_by_channel {
+        return write_output(
+            args,
+            options,
+            args.format,
+            events,
+            setup_len,
+            extractor.markers(),
+            output,
+        );
+    }
+    let output = output.context("--split-by-channel needs an output file")?;
+    for (channel, (events, setup_len)) in split_by_channel(events, setup_len) {
+        let format = options
+            .channel_formats
+            .get(&channel)
+            .copied()
+            .unwrap_or(args.format);
+        write_output(
+            args,
+            options,
+            format,
+            events,
+            setup_len,
+            extractor.markers(),
+            Some(&channel_output_path(output, channel)),
+        )?;
+    }
+    Ok(())
+}
+
+/// Writes `events` in `format` to `output` or stdout.
+fn write_output(
+    args: &Args,
+    options: &Options,
+    format: OutputFormat,
+    mut events: Vec<MidiEvent>,
+    setup_len: usize,
+    markers: &[Marker],
+    output: Option<&Path>,
+) -> Result<()> {
+    // compact output groups on absolute times and converts per line (see `compact_lines`)
+    let compact = options.template.is_none() && format == OutputFormat::Compact;
+    if args.delta_time && !compact {
+        cli::to_delta_times(&mut events, args.delta_from_zero);
+    }
+
+    let mut out: Box<dyn Write> = match output {
+        Some(path) => {
+            Box::new(BufWriter::new(File::create(path).with_context(|| {
+                format!("create output file {}", path.display())
+            })?))
+        }
+        None => Box::new(io::stdout().lock()),
+    };
+
+    let written = if args.count_only {
+        writeln!(out, "{}", events.len())
+    } else {
+        write_events(&mut out, args, options, format, &events, setup_len, markers)
+    };
+    // flush even when writing failed part way, so the lines that did make it are kept
+    let flushed = out.flush();
+    written.context("write output")?;
+    flushed.context("flush output")?;
+    Ok(())
+}
+
+/// Splits events by output channel, keeping their order. Each channel gets its own share of
+/// the setup block, which stays at the front.
+fn split_by_channel(
+    events: Vec<MidiEvent>,
+    setup_len: usize,
+) -> BTreeMap<u8, (Vec<MidiEvent>, usize)> {
+    let mut channels: BTreeMap<u8, (Vec<MidiEvent>, usize)> = BTreeMap::new();
+    for (i, event) in events.into_iter().enumerate() {
+        let (channel_events, channel_setup_len) = channels.entry(event.channel).or_default();
+        if i < setup_len {
+            *channel_setup_len += 1;
+        }
+        channel_events.push(event);
+    }
+    channels
+}
+
+/// `song.txt` becomes `song.ch4.txt` for channel 4.
+fn channel_output_path(output: &Path, channel: u8) -> PathBuf {
+    let mut name = output.file_stem().unwrap_or_default().to_os_string();
+    name.push(format!(".ch{}", channel));
+    if let Some(extension) = output.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+    output.with_file_name(name)
+}
+
+/// Flushes the wrapped writer after every `interval` lines (never, for 0).
+struct PeriodicFlush<'a> {
+    out: &'a mut dyn Write,
+    interval: usize,
+    lines: usize,
+}
+
+impl<'a> PeriodicFlush<'a> {
+    fn new(out: &'a mut dyn Write, interval: usize) -> Self {
+        Self {
+            out,
+            interval,
+            lines: 0,
+        }
+    }
+}
+
+impl Write for PeriodicFlush<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.out.write(buf)?;
+        self.lines += buf[..written].iter().filter(|b| **b == b'\n').count();
+        if self.interval > 0 && self.lines >= self.interval {
+            self.lines = 0;
+            self.out.flush()?;
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+fn print_stats(stats: &cli::EventStats) {
+    eprintln!("Stats:");
+    eprintln!("  events: {}", stats.total);
+    for (kind, count) in &stats.per_type {
+        eprintln!("    {}: {}", kind, count);
+    }
+    eprintln!("  channels: {}", stats.channels);
+    eprintln!("  tempo changes: {}", stats.tempo_changes);
+    match stats.final_timestamp {
+        Some(t) => eprintln!("  final timestamp: {:.3}s", t),
+        None => eprintln!("  final timestamp: -"),
+    }
+    match stats.duration {
+        Some(d) => eprintln!("  duration: {:.3}s", d),
+        None => eprintln!("  duration: -"),
+    }
+}
+
+fn load_midi_file(path: &str) -> Result<MidiFile> {
+    if path == "-" {
+        // buffer it all first so a parse error can't leave stdin half read
+        let mut bytes = Vec::new();
+        io::stdin()
+            .read_to_end(&mut bytes)
+            .context("read midi file from stdin")?;
+        MidiFile::read(io::Cursor::new(bytes)).context("parse midi file from stdin")
+    } else {
+        MidiFile::load(path).context("load midi file")
+    }
+}
+
+/// Parses `1=4,10=5` into a map from 0-based source channel to 1-based output channel.
+fn parse_channel_remap(value: &str) -> Result<HashMap<u8, u8>> {
+    let mut remap = HashMap::new();
+    for pair in value.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let (from, to) = pair
+            .split_once('=')
+            .with_context(|| format!("expected FROM=TO, got '{}'", pair))?;
+        let channel = |part: &str| -> Result<u8> {
+            match part.trim().parse::<u8>() {
+                Ok(channel @ 1..=16) => Ok(channel),
+                _ => bail!("'{}' is not a channel between 1 and 16", part),
+            }
+        };
+        remap.insert(channel(from)? - 1, channel(to)?);
+    }
+    Ok(remap)
+}
+
+/// Parses a `--channel-format` value like `10:json` into the (1-based) channel and its format.
+fn parse_channel_format(value: &str) -> Result<(u8, OutputFormat)> {
+    let (channel, format) = value
+        .split_once(':')
+        .with_context(|| format!("expected CH:FORMAT, got '{}'", value))?;
+    let channel = match channel.trim().parse::<u8>() {
+        Ok(channel @ 1..=16) => channel,
+        _ => bail!("'{}' is not a channel between 1 and 16", channel),
+    };
+    match OutputFormat::from_str(format.trim(), true) {
+        Ok(OutputFormat::ReaperMarkers) => {
+            bail!(
+                "reaper-markers lists markers, not channel events, so it can't be used per channel"
+            )
+        }
+        Ok(format) => Ok((channel, format)),
+        Err(_) => bail!("unknown format '{}'", format),
+    }
+}
+
+// keeps a --quantize grid's tick count well inside u32 at any PPQ
+const MAX_SUBDIVISION_PART: u32 = 1024;
+
+/// Parses a note length like `1/16` into `(numerator, denominator)` of a whole note.
+fn parse_subdivision(value: &str) -> Result<(u32, u32)> {
+    let (numerator, denominator) = value
+        .split_once('/')
+        .with_context(|| format!("expected a note length like 1/16, got '{}'", value))?;
+    let parse = |part: &str| -> Result<u32> {
+        match part.trim().parse::<u32>() {
+            Ok(n @ 1..=MAX_SUBDIVISION_PART) => Ok(n),
+            _ => bail!(
+                "'{}' is not a number from 1 to {}",
+                part,
+                MAX_SUBDIVISION_PART
+            ),
+        }
+    };
+    Ok((parse(numerator)?, parse(denominator)?))
+}
+
+/// Parses `A:B` into two numbers, each at most `max`.
+fn parse_value_pair(value: &str, max: u8) -> Result<(u8, u8)> {
+    let (a, b) = value
+        .split_once(':')
+        .with_context(|| format!("expected A:B, got '{}'", value))?;
+    let parse = |part: &str| -> Result<u8> {
+        let n = part
+            .trim()
+            .parse::<u8>()
+            .with_context(|| format!("'{}' is not a number", part))?;
+        if n > max {
+            bail!("{} is out of range 0..={}", n, max);
+        }
+        Ok(n)
+    };
+    Ok((parse(a)?, parse(b)?))
+}
+
+fn write_events(
+    out: &mut dyn Write,
+    args: &Args,
+    options: &Options,
+    format: OutputFormat,
+    emitted: &[MidiEvent],
+    setup_len: usize,
+    markers: &[Marker],
+) -> io::Result<()> {
+    let scene_map = options.scene_map.as_ref();
+    let out = &mut PeriodicFlush::new(out, args.flush_interval);
+    let total = emitted.len();
+    let emitted = match args.preview {
+        Some(n) => &emitted[..n.min(total)],
+        None => emitted,
+    };
+
+    match (options.template.as_ref(), format) {
+        (Some(formatter), _) => {
+            for event in emitted {
+                writeln!(out, "{}", formatter.format(event))?;
+            }
+        }
+        (None, OutputFormat::Stagetraxx) => {
+            let formatter = formatter::StageTraxxFormatter::new()
+                .with_note_names(args.note_names)
+                .with_always_hours(args.always_hours)
+                .with_time_precision(args.time_precision)
+                .with_show_bars(args.show_bars)
+                .with_group_by_bar(args.group_by_bar)
+                .with_gm_names(args.gm_names)
+                .with_cc_names(args.cc_names)
+                .with_title(args.title.clone())
+                .with_artist(args.artist.clone());
+            if let Some(preamble) = formatter.preamble() {
+                writeln!(out, "{}", preamble)?;
+            }
+            let mut last_bar = None;
+            for (i, event) in emitted.iter().enumerate() {
+                if i == 0 && setup_len > 0 {
+                    writeln!(out, "; --- setup ---")?;
+                }
+                // the setup block is hoisted to 00:00, so it isn't part of any bar
+                if i >= setup_len {
+                    if let Some(divider) = formatter.bar_divider(last_bar, event) {
+                        writeln!(out, "{}", divider)?;
+                    }
+                    last_bar = event.bar_beat.map(|pos| pos.bar).or(last_bar);
+                }
+                let mut line = formatter.format(event);
+                if let Some(scene) = scene_map.and_then(|m| m.scene_for(event)) {
+                    line.push_str(&format!(" ; scene: {}", scene));
+                }
+                if args.annotate_track {
+                    line.push_str(&format!(" ; trk={}", event.track));
+                }
+                writeln!(out, "{}", line)?;
+            }
+        }
+        (None, OutputFormat::Json) => {
+            let formatter = formatter::JsonFormatter::new();
+            for event in emitted {
+                writeln!(out, "{}", formatter.format(event))?;
+            }
+        }
+        (None, OutputFormat::Csv) => {
+            let formatter = formatter::CsvFormatter::new();
+            if let Some(header) = formatter.header() {
+                writeln!(out, "{}", header)?;
+            }
+            for event in emitted {
+                writeln!(out, "{}", formatter.format(event))?;
+            }
+        }
+        (None, OutputFormat::Compact) => {
+            let formatter = formatter::CompactFormatter::new(&args.compact_delimiter)
+                .with_note_names(args.note_names)
+                .with_always_hours(args.always_hours)
+                .with_time_precision(args.time_precision);
+            let delta = args.delta_time.then_some(args.delta_from_zero);
+            for line in compact_lines(&formatter, emitted, args.time_epsilon, delta) {
+                writeln!(out, "{}", line)?;
+            }
+        }
+        (None, OutputFormat::ReaperMarkers) => {
+            let mut markers: Vec<(f64, String)> = markers
+                .iter()
+                .map(|m| (m.timestamp, m.name.clone()))
+                .collect();
+            if args.reaper_note_markers {
+                markers.extend(emitted.iter().filter_map(|e| match e.message {
+                    Message::NoteOn(note, _) | Message::Note(note, _, _) => {
+                        Some((e.timestamp, format!("N{}@{}", note, e.channel)))
+                    }
+                    _ => None,
+                }));
+            }
+            markers.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+            let formatter = formatter::ReaperMarkerFormatter::new();
+            for (i, (timestamp, name)) in markers.iter().enumerate() {
+                writeln!(out, "{}", formatter.format_marker(i + 1, *timestamp, name))?;
+            }
+        }
+    }
+
+    if args.preview.is_some() {
+        writeln!(out, "; ... ({} total events)", total)?;
+    }
+
+    Ok(())
+}
+
+/// One line per group of simultaneous events. With `delta` (`Some(first_from_zero)`, as for
+/// `to_delta_times`), each line shows the time since the previous line; events are still
+/// grouped on their absolute times, so they must not have been converted already.
+fn compact_lines<'a>(
+    formatter: &'a formatter::CompactFormatter,
+    events: &'a [MidiEvent],
+    epsilon_ms: f64,
+    delta: Option<bool>,
+) -> impl Iterator<Item = String> + 'a {
+    let mut previous = events
+        .first()
+        .filter(|_| delta == Some(true))
+        .map_or(0.0, |e| e.timestamp);
+    events
+        .chunk_by(move |a, b| cli::same_time(a.timestamp, b.timestamp, epsilon_ms))
+        .map(move |group| {
+            if delta.is_none() {
+                return formatter.format_group(group);
+            }
+            let timestamp = group[0].timestamp;
+            let line = formatter.format_group_at((timestamp - previous).max(0.0), group);
+            previous = timestamp;
+            line
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compact_delta_times_group_on_absolute_times() {
+        let events = parse_cue_list(
+            "[midi@00:01.000: N36.100@1]\n\
+             [midi@00:01.000: N38.100@1]\n\
+             [midi@00:01.500: N42.100@1]\n\
+             [midi@00:02.000: N36.100@1]\n",
+        )
+        .unwrap();
+        let formatter = formatter::CompactFormatter::new(" ");
+        let lines = |delta| compact_lines(&formatter, &events, 0.0, delta).collect::<Vec<_>>();
+        // the last two lines share a delta but not a time, so they stay apart
+        assert_eq!(
+            lines(Some(false)),
+            [
+                "00:01.000 N36.100@1 N38.100@1",
+                "00:00.500 N42.100@1",
+                "00:00.500 N36.100@1",
+            ]
+        );
+        assert_eq!(lines(Some(true))[0], "00:00.000 N36.100@1 N38.100@1");
+        assert_eq!(lines(None)[2], "00:02.000 N36.100@1");
+    }
+
+    #[test]
+    fn channel_format_pairs_a_channel_with_a_format() {
+        assert_eq!(
+            parse_channel_format("10:json").unwrap(),
+            (10, OutputFormat::Json)
+        );
+        assert_eq!(
+            parse_channel_format("1: Compact").unwrap(),
+            (1, OutputFormat::Compact)
+        );
+        assert!(parse_channel_format("17:json").is_err());
+        assert!(parse_channel_format("4:reaper-markers").is_err());
+        assert!(parse_channel_format("4:xml").is_err());
+        assert!(parse_channel_format("json").is_err());
+    }
+
+    #[test]
+    fn split_by_channel_keeps_each_channels_setup_block() {
+        let events = parse_cue_list(
+            "[midi@00:00.000: CC7.100@4]\n\
+             [midi@00:00.000: CC7.90@10]\n\
+             [midi@00:01.000: N36.100@10]\n\
+             [midi@00:01.000: N60.100@4]\n\
+             [midi@00:02.000: N38.100@10]\n",
+        )
+        .unwrap();
+        let channels = split_by_channel(events, 2);
+        let summary: Vec<(u8, usize, usize)> = channels
+            .iter()
+            .map(|(channel, (events, setup_len))| (*channel, events.len(), *setup_len))
+            .collect();
+        assert_eq!(summary, [(4, 2, 1), (10, 3, 1)]);
+        assert_eq!(channels[&10].0[2].timestamp, 2.0);
+    }
+
+    #[test]
+    fn channel_output_path_inserts_the_channel() {
+        assert_eq!(
+            channel_output_path(Path::new("out/song.txt"), 4),
+            Path::new("out/song.ch4.txt")
+        );
+        assert_eq!(
+            channel_output_path(Path::new("song"), 10),
+            Path::new("song.ch10")
+        );
+    }
+
+    #[test]
+    fn channel_remap_is_keyed_by_zero_based_source_channel() {
+        let remap = parse_channel_remap("1=10, 2=3").unwrap();
+        assert_eq!(remap, HashMap::from([(0, 10), (1, 3)]));
+        assert!(parse_channel_remap("0=1").is_err());
+        assert!(parse_channel_remap("1=17").is_err());
+        assert!(parse_channel_remap("1:2").is_err());
+    }
+
+    // records how much had been written at each flush
+    #[derive(Default)]
+    struct FlushLog {
+        written: Vec<u8>,
+        flushed_at: Vec<usize>,
+    }
+
+    impl Write for FlushLog {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.flushed_at.push(self.written.len());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn periodic_flush_flushes_every_interval_lines() {
+        let mut log = FlushLog::default();
+        let mut out = PeriodicFlush::new(&mut log, 2);
+        for i in 0..5 {
+            writeln!(out, "{}", i).unwrap();
+        }
+        assert_eq!(log.flushed_at, [4, 8]);
+    }
+
+    #[test]
+    fn periodic_flush_interval_zero_never_flushes_on_its_own() {
+        let mut log = FlushLog::default();
+        let mut out = PeriodicFlush::new(&mut log, 0);
+        for i in 0..5 {
+            writeln!(out, "{}", i).unwrap();
+        }
+        assert!(log.flushed_at.is_empty());
+    }
+
+    #[test]
+    fn batch_outputs_with_the_same_name_collide() {
+        let midi_files = vec!["a/song.mid".to_string(), "b/song.mid".to_string()];
+        let outputs: Vec<PathBuf> = midi_files
+            .iter()
+            .map(|f| batch_output_path(f, Some(Path::new("out"))))
+            .collect();
+        let err = check_output_collisions(&midi_files, &outputs).unwrap_err();
+        assert!(
+            err.to_string().contains("a/song.mid and b/song.mid"),
+            "{}",
+            err
+        );
+
+        let outputs: Vec<PathBuf> = midi_files
+            .iter()
+            .map(|f| batch_output_path(f, None))
+            .collect();
+        assert!(check_output_collisions(&midi_files, &outputs).is_ok());
+    }
+}
@@ -1,14 +1,38 @@
 #[derive(Debug)]
+#[non_exhaustive]
 pub struct MidiEvent {
     pub timestamp: f64, // in seconds
     pub message: Message,
     pub channel: u8,
+    pub track: usize,              // index of the source track
+    pub bar_beat: Option<BarBeat>, // None for SMPTE division files, which have no meter
 }
 
-#[derive(Debug)]
+/// Musical position of an event: 1-based bar and beat, plus ticks into the beat.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BarBeat {
+    pub bar: u32,
+    pub beat: u32,
+    pub tick: u32,
+}
+
+#[derive(Debug, PartialEq)]
 #[allow(dead_code)]
+#[non_exhaustive]
 pub enum Message {
     NoteOn(u8, u8),
     NoteOff(u8, u8),
     ControlChange(u8, u8),
+    ControlChange14(u8, u16), // coarse controller, 14-bit value (see transforms::merge_cc14)
+    ProgramChange(u8),
+    PitchBend(i16),       // centered 14-bit value, -8192..=8191
+    ChannelPressure(u8),  // pressure
+    PolyPressure(u8, u8), // note, pressure
+    Note(u8, u8, f64),    // note, velocity, duration in seconds (see transforms::notes_as_duration)
+}
+
+#[derive(Debug)]
+pub struct Marker {
+    pub timestamp: f64, // in seconds
+    pub name: String,
 }
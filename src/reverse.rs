@@ -0,0 +1,321 @@
+//! The reverse path: reads a StageTraxx cue list back into events and writes them out as
+//! a Type-0 MIDI file, so a hand edited cue list can be previewed in a DAW.
+
+use anyhow::{anyhow, bail, Context, Result};
+use std::io::{self, Write};
+
+use crate::formatter::{parse_midi_time, NOTE_NAMES};
+use crate::midi_event::{Message, MidiEvent};
+use crate::timing::MICROS_PER_SEC;
+
+/// Parses `[midi@MM:SS.mmm: TOKEN@channel]` lines, as written by `StageTraxxFormatter`.
+/// Blank lines, lines starting with `;` and trailing `; comments` are ignored.
+pub fn parse_cue_list(contents: &str) -> Result<Vec<MidiEvent>> {
+    let mut events = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.split(" ;").next().unwrap_or_default().trim();
+        if line.is_empty() || line.starts_with(';') {
+            continue;
+        }
+        let event = parse_cue(line).with_context(|| format!("line {}", i + 1))?;
+        events.push(event);
+    }
+    Ok(events)
+}
+
+fn parse_cue(line: &str) -> Result<MidiEvent> {
+    let inner = line
+        .strip_prefix("[midi@")
+        .and_then(|l| l.strip_suffix(']'))
+        .ok_or_else(|| {
+            anyhow!(
+                "expected [midi@<time>: <message>@<channel>], got '{}'",
+                line
+            )
+        })?;
+    let (time, token) = inner
+        .split_once(": ")
+        .ok_or_else(|| anyhow!("missing ': ' between time and message in '{}'", line))?;
+    let (message, channel) = token
+        .rsplit_once('@')
+        .ok_or_else(|| anyhow!("missing '@<channel>' in '{}'", token))?;
+
+    let channel = match channel.parse::<u8>() {
+        Ok(channel @ 1..=16) => channel,
+        _ => bail!("invalid channel '{}' (expected 1..=16)", channel),
+    };
+    Ok(MidiEvent {
+        timestamp: parse_midi_time(time)?,
+        message: parse_message(message)?,
+        channel,
+        track: 0,
+        bar_beat: None,
+    })
+}
+
+fn parse_message(token: &str) -> Result<Message> {
+    let message = if let Some(args) = token.strip_prefix("HCC") {
+        let (controller, value) = split_args(args)?;
+        Message::ControlChange14(parse_u7(controller)?, parse_number(value, 0x3FFF)? as u16)
+    } else if let Some(args) = token.strip_prefix("CC") {
+        let (controller, value) = split_args(args)?;
+        Message::ControlChange(parse_u7(controller)?, parse_u7(value)?)
+    } else if let Some(args) = token.strip_prefix("PAT") {
+        let (note, pressure) = split_args(args)?;
+        Message::PolyPressure(parse_note(note)?, parse_u7(pressure)?)
+    } else if let Some(program) = token.strip_prefix("PC") {
+        Message::ProgramChange(parse_u7(program)?)
+    } else if let Some(value) = token.strip_prefix("PB") {
+        let value: i16 = value
+            .parse()
+            .map_err(|_| anyhow!("invalid pitch bend '{}'", value))?;
+        if !(-8192..=8191).contains(&value) {
+            bail!("pitch bend {} is out of range -8192..=8191", value);
+        }
+        Message::PitchBend(value)
+    } else if let Some(pressure) = token.strip_prefix("AT") {
+        Message::ChannelPressure(parse_u7(pressure)?)
+    } else if let Some(args) = token.strip_prefix('N') {
+        // N60.100, N60.0 (off), NC4.100 (note names) or N60.100/1.250 (with duration)
+        let (args, duration) = match args.split_once('/') {
+            Some((args, duration)) => (args, Some(duration)),
+            None => (args, None),
+        };
+        let (note, velocity) = split_args(args)?;
+        let (note, velocity) = (parse_note(note)?, parse_u7(velocity)?);
+        match duration {
+            Some(duration) => {
+                let duration: f64 = duration
+                    .parse()
+                    .ok()
+                    .filter(|d: &f64| *d >= 0.0)
+                    .ok_or_else(|| anyhow!("invalid duration '{}'", duration))?;
+                Message::Note(note, velocity, duration)
+            }
+            None if velocity == 0 => Message::NoteOff(note, 0),
+            None => Message::NoteOn(note, velocity),
+        }
+    } else {
+        bail!("unknown message '{}'", token);
+    };
+    Ok(message)
+}
+
+// "60.100" -> ("60", "100"); the last dot splits, so "C-1.100" keeps its note name intact
+fn split_args(args: &str) -> Result<(&str, &str)> {
+    args.rsplit_once('.')
+        .ok_or_else(|| anyhow!("expected <a>.<b>, got '{}'", args))
+}
+
+fn parse_number(value: &str, max: u32) -> Result<u32> {
+    match value.parse::<u32>() {
+        Ok(n) if n <= max => Ok(n),
+        _ => bail!("invalid value '{}' (expected 0..={})", value, max),
+    }
+}
+
+fn parse_u7(value: &str) -> Result<u8> {
+    Ok(parse_number(value, 127)? as u8)
+}
+
+// a note number, or a pitch name as written by --note-names (MIDI 60 = C4)
+fn parse_note(note: &str) -> Result<u8> {
+    if note.starts_with(|c: char| c.is_ascii_digit()) {
+        return parse_u7(note);
+    }
+    let split = note
+        .find(|c: char| c == '-' || c.is_ascii_digit())
+        .ok_or_else(|| anyhow!("invalid note '{}'", note))?;
+    let (name, octave) = note.split_at(split);
+    let pitch_class = NOTE_NAMES
+        .iter()
+        .position(|n| *n == name)
+        .ok_or_else(|| anyhow!("invalid note name '{}'", note))?;
+    let octave: i32 = octave
+        .parse()
+        .map_err(|_| anyhow!("invalid octave in note '{}'", note))?;
+    let number = (octave + 1) * 12 + pitch_class as i32;
+    u8::try_from(number)
+        .ok()
+        .filter(|n| *n <= 127)
+        .ok_or_else(|| anyhow!("note '{}' is out of range", note))
+}
+
+/// Writes `events` as a single track (Type 0) MIDI file with `ppq` pulses per quarter note
+/// and one fixed tempo. Notes with a duration become a NoteOn/NoteOff pair. A 14-bit CC is
+/// split into its coarse byte and a fine byte on the `cc14` partner for that controller, or
+/// on controller + 32 (the standard LSB) otherwise.
+pub fn write_type0<W: Write>(
+    events: &[MidiEvent],
+    ppq: u16,
+    bpm: f64,
+    cc14: Option<(u8, u8)>,
+    out: &mut W,
+) -> io::Result<()> {
+    let ticks_per_sec = ppq as f64 * bpm / 60.0;
+    let to_tick = |seconds: f64| (seconds.max(0.0) * ticks_per_sec).round() as u32;
+
+    let mut messages: Vec<(u32, Vec<u8>)> = Vec::new();
+    for event in events {
+        let tick = to_tick(event.timestamp);
+        let ch = (event.channel.clamp(1, 16) - 1) & 0x0F;
+        match event.message {
+            Message::NoteOn(note, velocity) => {
+                messages.push((tick, vec![0x90 | ch, note, velocity]))
+            }
+            Message::NoteOff(note, _) => messages.push((tick, vec![0x80 | ch, note, 0])),
+            Message::Note(note, velocity, duration) => {
+                messages.push((tick, vec![0x90 | ch, note, velocity]));
+                messages.push((
+                    to_tick(event.timestamp + duration),
+                    vec![0x80 | ch, note, 0],
+                ));
+            }
+            Message::ControlChange(controller, value) => {
+                messages.push((tick, vec![0xB0 | ch, controller, value]))
+            }
+            Message::ControlChange14(controller, value) => {
+                let fine = match cc14 {
+                    Some((coarse, fine)) if coarse == controller => fine,
+                    _ => (controller + 32) & 0x7F,
+                };
+                messages.push((tick, vec![0xB0 | ch, controller, (value >> 7) as u8 & 0x7F]));
+                messages.push((tick, vec![0xB0 | ch, fine, value as u8 & 0x7F]));
+            }
+            Message::ProgramChange(program) => messages.push((tick, vec![0xC0 | ch, program])),
+            Message::PitchBend(value) => {
+                let raw = (value as i32 + 0x2000).clamp(0, 0x3FFF) as u16;
+                messages.push((tick, vec![0xE0 | ch, (raw & 0x7F) as u8, (raw >> 7) as u8]));
+            }
+            Message::ChannelPressure(pressure) => messages.push((tick, vec![0xD0 | ch, pressure])),
+            Message::PolyPressure(note, pressure) => {
+                messages.push((tick, vec![0xA0 | ch, note, pressure]))
+            }
+        }
+    }
+    // stable, so simultaneous messages keep the cue list's order
+    messages.sort_by_key(|(tick, _)| *tick);
+
+    let mut track = Vec::new();
+    // tempo meta event at tick 0
+    let tempo = (MICROS_PER_SEC * 60.0 / bpm).round() as u32;
+    write_vlq(&mut track, 0);
+    track.extend_from_slice(&[0xFF, 0x51, 0x03]);
+    track.extend_from_slice(&tempo.to_be_bytes()[1..]);
+
+    let mut last_tick = 0;
+    for (tick, bytes) in &messages {
+        write_vlq(&mut track, tick - last_tick);
+        track.extend_from_slice(bytes);
+        last_tick = *tick;
+    }
+    // end of track
+    write_vlq(&mut track, 0);
+    track.extend_from_slice(&[0xFF, 0x2F, 0x00]);
+
+    out.write_all(b"MThd")?;
+    out.write_all(&6u32.to_be_bytes())?;
+    out.write_all(&0u16.to_be_bytes())?; // format 0
+    out.write_all(&1u16.to_be_bytes())?; // one track
+    out.write_all(&ppq.to_be_bytes())?;
+    out.write_all(b"MTrk")?;
+    out.write_all(&(track.len() as u32).to_be_bytes())?;
+    out.write_all(&track)
+}
+
+// MIDI variable length quantity: 7 bits per byte, most significant first,
+// with the high bit set on every byte but the last
+fn write_vlq(out: &mut Vec<u8>, value: u32) {
+    let mut bytes = vec![(value & 0x7F) as u8];
+    let mut value = value >> 7;
+    while value > 0 {
+        bytes.push((value & 0x7F) as u8 | 0x80);
+        value >>= 7;
+    }
+    out.extend(bytes.iter().rev());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_cues_skipping_comments() {
+        let events = parse_cue_list(
+            "; title: Song\n\n[midi@00:01.500: NC4.100@2] ; scene: Intro\n[midi@01:00:00.000: PB-8192@16]\n",
+        )
+        .unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].timestamp, 1.5);
+        assert_eq!(events[0].message, Message::NoteOn(60, 100));
+        assert_eq!(events[0].channel, 2);
+        assert_eq!(events[1].timestamp, 3600.0);
+        assert_eq!(events[1].message, Message::PitchBend(-8192));
+    }
+
+    #[test]
+    fn rejects_bad_cues_with_their_line() {
+        for bad in [
+            "[midi@00:00.000: N60.100@17]",
+            "[midi@00:00.000: N60.128@1]",
+            "[midi@00:00.000: XYZ@1]",
+            "midi@00:00.000: N60.100@1",
+        ] {
+            let err = parse_cue_list(&format!("; ok\n{}", bad)).unwrap_err();
+            assert!(format!("{:#}", err).starts_with("line 2"), "{:#}", err);
+        }
+    }
+
+    #[test]
+    fn cue_list_round_trips_through_a_midi_file() {
+        use crate::formatter::{MidiFormatter, StageTraxxFormatter};
+        use crate::Extractor;
+
+        let cues = "\
+[midi@00:00.000: PC5@1]
+[midi@00:00.000: N60.100@1]
+[midi@00:00.500: CC7.64@2]
+[midi@00:00.750: PB-100@3]
+[midi@00:01.000: N60.0@1]
+[midi@00:02.000: HCC7.8193@2]
+";
+        let mut bytes = Vec::new();
+        write_type0(&parse_cue_list(cues).unwrap(), 480, 120.0, None, &mut bytes).unwrap();
+        let midi_file = midi_file::MidiFile::read(&bytes[..]).unwrap();
+        let mut events = Extractor::new(midi_file, None).unwrap().run().unwrap();
+        crate::transforms::merge_cc14(&mut events, 7, 39);
+
+        let formatter = StageTraxxFormatter::new();
+        let lines: Vec<String> = events.iter().map(|e| formatter.format(e)).collect();
+        assert_eq!(lines.join("\n") + "\n", cues);
+    }
+
+    fn cc14_event(controller: u8, value: u16) -> MidiEvent {
+        MidiEvent {
+            timestamp: 0.0,
+            message: Message::ControlChange14(controller, value),
+            channel: 1,
+            track: 0,
+            bar_beat: None,
+        }
+    }
+
+    // the track data after the tempo event, up to the end of track
+    fn written_messages(events: &[MidiEvent], cc14: Option<(u8, u8)>) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_type0(events, 480, 120.0, cc14, &mut out).unwrap();
+        out[22 + 7..out.len() - 4].to_vec()
+    }
+
+    #[test]
+    fn cc14_fine_byte_defaults_to_controller_plus_32() {
+        let bytes = written_messages(&[cc14_event(7, 0x2001)], None);
+        assert_eq!(bytes, [0, 0xB0, 7, 0x40, 0, 0xB0, 39, 0x01]);
+    }
+
+    #[test]
+    fn cc14_fine_byte_goes_to_the_configured_partner() {
+        let bytes = written_messages(&[cc14_event(20, 0x2001)], Some((20, 21)));
+        assert_eq!(bytes, [0, 0xB0, 20, 0x40, 0, 0xB0, 21, 0x01]);
+    }
+}
@@ -0,0 +1,111 @@
+use anyhow::{anyhow, Context, Result};
+use std::collections::HashMap;
+use std::fs;
+
+use crate::midi_event::{Message, MidiEvent};
+
+/// Maps note numbers (optionally per channel) to human readable scene names.
+///
+/// Loaded from a CSV file with one mapping per line, either `note,name` or
+/// `note,channel,name`. Blank lines and lines starting with `#` are ignored.
+/// Channel-specific entries win over entries that apply to every channel.
+pub struct SceneMap {
+    any_channel: HashMap<u8, String>,
+    per_channel: HashMap<(u8, u8), String>,
+}
+
+impl SceneMap {
+    pub fn load(path: &str) -> Result<Self> {
+        let contents =
+            fs::read_to_string(path).with_context(|| format!("read scene map {}", path))?;
+        Self::parse(&contents).with_context(|| format!("parse scene map {}", path))
+    }
+
+    pub fn parse(contents: &str) -> Result<Self> {
+        let mut any_channel = HashMap::new();
+        let mut per_channel = HashMap::new();
+
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.splitn(3, ',').map(|f| f.trim()).collect();
+            let note: u8 = fields[0]
+                .parse()
+                .map_err(|_| anyhow!("line {}: invalid note number '{}'", i + 1, fields[0]))?;
+            match fields[..] {
+                [_, name] => {
+                    any_channel.insert(note, name.to_string());
+                }
+                [_, channel, name] => {
+                    let channel: u8 = channel
+                        .parse()
+                        .map_err(|_| anyhow!("line {}: invalid channel '{}'", i + 1, channel))?;
+                    per_channel.insert((note, channel), name.to_string());
+                }
+                _ => {
+                    return Err(anyhow!(
+                        "line {}: expected 'note,name' or 'note,channel,name'",
+                        i + 1
+                    ))
+                }
+            }
+        }
+
+        Ok(Self {
+            any_channel,
+            per_channel,
+        })
+    }
+
+    /// Returns the scene name for a NoteOn (or duration note) event, if one is mapped.
+    pub fn scene_for(&self, event: &MidiEvent) -> Option<&str> {
+        match event.message {
+            Message::NoteOn(note, _) | Message::Note(note, _, _) => self
+                .per_channel
+                .get(&(note, event.channel))
+                .or_else(|| self.any_channel.get(&note))
+                .map(|name| name.as_str()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note_on(note: u8, channel: u8) -> MidiEvent {
+        MidiEvent {
+            timestamp: 0.0,
+            message: Message::NoteOn(note, 100),
+            channel,
+            track: 0,
+            bar_beat: None,
+        }
+    }
+
+    #[test]
+    fn channel_specific_entries_win() {
+        let map = SceneMap::parse("# scenes\n36, Intro\n\n36,2,Intro (stage left)\n").unwrap();
+        assert_eq!(map.scene_for(&note_on(36, 1)), Some("Intro"));
+        assert_eq!(map.scene_for(&note_on(36, 2)), Some("Intro (stage left)"));
+        assert_eq!(map.scene_for(&note_on(37, 1)), None);
+    }
+
+    #[test]
+    fn only_note_ons_have_scenes() {
+        let map = SceneMap::parse("36,Intro").unwrap();
+        let mut off = note_on(36, 1);
+        off.message = Message::NoteOff(36, 0);
+        assert_eq!(map.scene_for(&off), None);
+    }
+
+    #[test]
+    fn reports_the_bad_line() {
+        let err = SceneMap::parse("36,Intro\nkick,Verse").err().unwrap();
+        assert!(err.to_string().contains("line 2"), "{}", err);
+    }
+}
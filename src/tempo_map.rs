@@ -0,0 +1,174 @@
+use anyhow::{anyhow, bail, Context, Result};
+use std::fs;
+
+use crate::timing::{DEFAULT_BPM, MICROS_PER_SEC};
+
+#[derive(Debug, Clone, Copy)]
+enum Position {
+    Tick(u32),
+    Seconds(f64),
+}
+
+/// A user supplied tempo map that replaces the tempo events embedded in the file.
+///
+/// Loaded from a CSV file of `tick,bpm` lines, or `seconds,bpm` lines when the
+/// file starts with a `seconds,bpm` header (a `tick,bpm` header is also accepted).
+/// Blank lines and lines starting with `#` are ignored.
+#[derive(Debug)]
+pub struct TempoMap {
+    entries: Vec<(Position, f64)>,
+}
+
+impl TempoMap {
+    pub fn load(path: &str) -> Result<Self> {
+        let contents =
+            fs::read_to_string(path).with_context(|| format!("read tempo map {}", path))?;
+        Self::parse(&contents).with_context(|| format!("parse tempo map {}", path))
+    }
+
+    pub fn parse(contents: &str) -> Result<Self> {
+        let mut in_seconds = false;
+        let mut entries: Vec<(Position, f64)> = Vec::new();
+
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (position, bpm) = line
+                .split_once(',')
+                .map(|(p, b)| (p.trim(), b.trim()))
+                .ok_or_else(|| anyhow!("line {}: expected 'position,bpm'", i + 1))?;
+
+            if entries.is_empty() && bpm.eq_ignore_ascii_case("bpm") {
+                in_seconds = match position.to_ascii_lowercase().as_str() {
+                    "tick" | "ticks" => false,
+                    "second" | "seconds" => true,
+                    other => bail!("line {}: unknown position column '{}'", i + 1, other),
+                };
+                continue;
+            }
+
+            let position = if in_seconds {
+                let seconds: f64 = position
+                    .parse()
+                    .map_err(|_| anyhow!("line {}: invalid seconds '{}'", i + 1, position))?;
+                if !seconds.is_finite() || seconds < 0.0 {
+                    bail!("line {}: seconds must be >= 0", i + 1);
+                }
+                Position::Seconds(seconds)
+            } else {
+                Position::Tick(
+                    position
+                        .parse()
+                        .map_err(|_| anyhow!("line {}: invalid tick '{}'", i + 1, position))?,
+                )
+            };
+
+            let bpm: f64 = bpm
+                .parse()
+                .map_err(|_| anyhow!("line {}: invalid bpm '{}'", i + 1, bpm))?;
+            if !bpm.is_finite() || bpm <= 0.0 {
+                bail!("line {}: bpm must be positive", i + 1);
+            }
+
+            if let Some((previous, _)) = entries.last() {
+                let sorted = match (previous, &position) {
+                    (Position::Tick(a), Position::Tick(b)) => a < b,
+                    (Position::Seconds(a), Position::Seconds(b)) => a < b,
+                    _ => unreachable!("positions share a single unit"),
+                };
+                if !sorted {
+                    bail!(
+                        "line {}: tempo map positions must be strictly increasing",
+                        i + 1
+                    );
+                }
+            }
+
+            entries.push((position, bpm));
+        }
+
+        if entries.is_empty() {
+            bail!("tempo map is empty");
+        }
+
+        Ok(Self { entries })
+    }
+
+    /// Resolves the map into `(absolute tick, microseconds per quarter note)` pairs.
+    /// Positions given in seconds are converted using the map's own preceding tempos,
+    /// starting from the default 120 BPM. Fails when a position lands past the last tick
+    /// a MIDI file can address.
+    pub fn tempo_changes(&self, pulses_per_qn: u16) -> Result<Vec<(u32, u32)>> {
+        let mut changes = Vec::with_capacity(self.entries.len());
+        let mut last_tick: u32 = 0;
+        let mut last_seconds = 0.0;
+        let mut current_bpm = DEFAULT_BPM;
+
+        for (position, bpm) in &self.entries {
+            let tick = match position {
+                Position::Tick(tick) => *tick,
+                Position::Seconds(seconds) => {
+                    let beats = (seconds - last_seconds) * current_bpm / 60.0;
+                    last_seconds = *seconds;
+                    let ticks = (beats * pulses_per_qn as f64).round();
+                    (ticks <= u32::MAX as f64)
+                        .then_some(ticks as u32)
+                        .and_then(|ticks| last_tick.checked_add(ticks))
+                        .ok_or_else(|| {
+                            anyhow!("tempo map position {}s is past the last MIDI tick", seconds)
+                        })?
+                }
+            };
+            last_tick = tick;
+            current_bpm = *bpm;
+            changes.push((tick, (MICROS_PER_SEC * 60.0 / bpm).round() as u32));
+        }
+
+        Ok(changes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tick_positions_convert_to_micros_per_quarter() {
+        let map = TempoMap::parse("# intro\n0,120\n1920, 60\n").unwrap();
+        assert_eq!(
+            map.tempo_changes(480).unwrap(),
+            [(0, 500_000), (1920, 1_000_000)]
+        );
+    }
+
+    #[test]
+    fn second_positions_follow_the_preceding_tempos() {
+        // 2s at 120 BPM is four beats, then 1s at 60 BPM is one more
+        let map = TempoMap::parse("seconds,bpm\n0,120\n2,60\n3,90\n").unwrap();
+        assert_eq!(
+            map.tempo_changes(480).unwrap(),
+            [(0, 500_000), (1920, 1_000_000), (2400, 666_667)]
+        );
+    }
+
+    #[test]
+    fn second_positions_past_the_last_tick_are_an_error() {
+        // 5,000,000s at 120 BPM and 480 PPQ is 4.8 billion ticks
+        let map = TempoMap::parse("seconds,bpm\n0,120\n5000000,120").unwrap();
+        assert!(map.tempo_changes(480).is_err());
+        // each step fits, their sum doesn't
+        let map = TempoMap::parse("seconds,bpm\n0,120\n4000000,120\n8000000,120").unwrap();
+        assert!(map.tempo_changes(480).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_maps() {
+        assert!(TempoMap::parse("").is_err());
+        assert!(TempoMap::parse("480,120\n0,90").is_err());
+        assert!(TempoMap::parse("0,-10").is_err());
+        assert!(TempoMap::parse("beats,bpm\n0,120").is_err());
+    }
+}
@@ -0,0 +1,133 @@
+//! Tick to seconds conversion, shared by everything that needs to place an event in time.
+
+// microseconds per second
+pub const MICROS_PER_SEC: f64 = 1_000_000.0;
+pub const DEFAULT_BPM: f64 = 120.0;
+pub(crate) const DEFAULT_TEMPO_MICROS_PER_QN: u32 = (MICROS_PER_SEC / (DEFAULT_BPM / 60.0)) as u32;
+
+/// How a file's delta times map to real time.
+#[derive(Clone, Copy, Debug)]
+pub enum TimeDivision {
+    /// Ticks are fractions of a quarter note, so their length follows the tempo.
+    PulsesPerQuarterNote(u16),
+    /// Ticks are fractions of an SMPTE frame: an absolute clock that ignores tempo.
+    Smpte {
+        frames_per_sec: f64,
+        ticks_per_frame: u8,
+    },
+}
+
+/// Seconds spanned by `ticks` at `ppq` pulses per quarter note and `tempo` microseconds
+/// per quarter note (MIDI's unit for tempo).
+pub fn seconds_for_ticks(ticks: u32, ppq: u16, tempo: u32) -> f64 {
+    let beats = ticks as f64 / ppq as f64;
+    beats * tempo as f64 / MICROS_PER_SEC
+}
+
+/// Seconds spanned by `ticks` under `division`; `tempo` only matters for PPQ files.
+pub fn ticks_to_seconds(ticks: u32, division: TimeDivision, tempo: u32) -> f64 {
+    match division {
+        TimeDivision::PulsesPerQuarterNote(ppq) => seconds_for_ticks(ticks, ppq, tempo),
+        TimeDivision::Smpte {
+            frames_per_sec,
+            ticks_per_frame,
+        } => ticks as f64 / (frames_per_sec * ticks_per_frame as f64),
+    }
+}
+
+/// A stretch of the timeline played at a single tempo, starting at `tick`.
+#[derive(Clone, Copy, Debug)]
+struct TempoSegment {
+    tick: u32,
+    seconds: f64, // elapsed time at `tick`
+    tempo: u32,   // microseconds per quarter note
+}
+
+/// Maps absolute ticks to seconds across every tempo change in a file.
+#[derive(Clone, Debug)]
+pub struct Timeline {
+    division: TimeDivision,
+    segments: Vec<TempoSegment>,
+}
+
+impl Timeline {
+    /// Builds the timeline from `(absolute tick, micros per qn)` changes sorted by tick.
+    /// The timeline starts at the default tempo (120 BPM) until the first change.
+    pub fn new(tempo_changes: &[(u32, u32)], division: TimeDivision) -> Self {
+        let mut segments = vec![TempoSegment {
+            tick: 0,
+            seconds: 0.0,
+            tempo: DEFAULT_TEMPO_MICROS_PER_QN,
+        }];
+        for &(tick, tempo) in tempo_changes {
+            let last_index = segments.len() - 1;
+            let last = segments[last_index];
+            if tick == last.tick {
+                // a later change at the same tick wins
+                segments[last_index].tempo = tempo;
+                continue;
+            }
+            segments.push(TempoSegment {
+                tick,
+                seconds: last.seconds + ticks_to_seconds(tick - last.tick, division, last.tempo),
+                tempo,
+            });
+        }
+        Self { division, segments }
+    }
+
+    /// Converts an absolute tick to seconds by walking the tempo segments.
+    pub fn seconds_at(&self, tick: u32) -> f64 {
+        // the first segment always starts at tick 0, so there is one at or before `tick`
+        let i = self.segments.partition_point(|s| s.tick <= tick);
+        let segment = self.segments[i - 1];
+        segment.seconds + ticks_to_seconds(tick - segment.tick, self.division, segment.tempo)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PPQ_480: TimeDivision = TimeDivision::PulsesPerQuarterNote(480);
+
+    #[test]
+    fn quarter_note_at_120_bpm_is_half_a_second() {
+        assert_eq!(seconds_for_ticks(480, 480, 500_000), 0.5);
+        assert_eq!(ticks_to_seconds(480, PPQ_480, 500_000), 0.5);
+    }
+
+    #[test]
+    fn doubling_the_tempo_halves_durations() {
+        let at_120 = seconds_for_ticks(1920, 480, 500_000);
+        let at_240 = seconds_for_ticks(1920, 480, 250_000);
+        assert_eq!(at_120, 2.0);
+        assert_eq!(at_240, at_120 / 2.0);
+    }
+
+    #[test]
+    fn smpte_ticks_ignore_tempo() {
+        let division = TimeDivision::Smpte {
+            frames_per_sec: 25.0,
+            ticks_per_frame: 40,
+        };
+        assert_eq!(ticks_to_seconds(1000, division, 500_000), 1.0);
+        assert_eq!(ticks_to_seconds(1000, division, 250_000), 1.0);
+    }
+
+    #[test]
+    fn timeline_sums_its_segments() {
+        // two beats at 120 BPM, then 60 BPM
+        let timeline = Timeline::new(&[(0, 500_000), (960, 1_000_000)], PPQ_480);
+        assert_eq!(timeline.seconds_at(480), 0.5);
+        assert_eq!(timeline.seconds_at(960), 1.0);
+        assert_eq!(timeline.seconds_at(1440), 2.0);
+    }
+
+    #[test]
+    fn timeline_starts_at_120_bpm() {
+        let timeline = Timeline::new(&[(960, 1_000_000)], PPQ_480);
+        assert_eq!(timeline.seconds_at(960), 1.0);
+        assert_eq!(timeline.seconds_at(1920), 3.0);
+    }
+}
@@ -0,0 +1,816 @@
+use crate::midi_event::{Message, MidiEvent};
+use crate::warn;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+
+/// Whether two timestamps (in seconds) count as simultaneous, given a tolerance in milliseconds.
+/// Every same-time comparison should go through here so they agree with each other.
+pub fn same_time(a: f64, b: f64, epsilon_ms: f64) -> bool {
+    (a - b).abs() <= epsilon_ms / 1000.0
+}
+
+/// Drops NoteOffs that land at the same time as a NoteOn, so a mutually exclusive scene
+/// being switched off can't cancel the one being switched on. NoteOffs with no NoteOn
+/// at their timestamp are kept. Expects events sorted by timestamp.
+pub fn skip_off_note_collisions(events: &mut Vec<MidiEvent>, epsilon_ms: f64) {
+    let mut keep = Vec::with_capacity(events.len());
+    for group in events.chunk_by(|a, b| same_time(a.timestamp, b.timestamp, epsilon_ms)) {
+        let has_note_on = group
+            .iter()
+            .any(|e| matches!(e.message, Message::NoteOn(..) | Message::Note(..)));
+        keep.extend(
+            group
+                .iter()
+                .map(|e| !(has_note_on && matches!(e.message, Message::NoteOff(..)))),
+        );
+    }
+
+    let mut keep = keep.into_iter();
+    events.retain(|_| keep.next().unwrap_or(true));
+}
+
+/// Shifts every event by `offset_ms` (negative is earlier), clamping at time 0.
+pub fn apply_offset(events: &mut [MidiEvent], offset_ms: i64) {
+    let offset = offset_ms as f64 / 1000.0;
+    for event in events {
+        event.timestamp = (event.timestamp + offset).max(0.0);
+    }
+}
+
+/// Rewrites each timestamp as the time since the previous event, so the output reads as
+/// delta times. The first event keeps its absolute time unless `first_from_zero` is set.
+/// Expects events sorted by timestamp; must be the last pass that looks at timestamps.
+pub fn to_delta_times(events: &mut [MidiEvent], first_from_zero: bool) {
+    let mut previous = events
+        .first()
+        .filter(|_| first_from_zero)
+        .map_or(0.0, |e| e.timestamp);
+    for event in events {
+        let timestamp = event.timestamp;
+        event.timestamp = (timestamp - previous).max(0.0);
+        previous = timestamp;
+    }
+}
+
+/// Keeps only events with `start <= timestamp <= end` (either bound may be open). With `rebase`,
+/// surviving timestamps are shifted so `start` becomes 00:00.
+pub fn apply_window(
+    events: &mut Vec<MidiEvent>,
+    start: Option<f64>,
+    end: Option<f64>,
+    rebase: bool,
+) {
+    events.retain(|e| {
+        start.is_none_or(|s| e.timestamp >= s) && end.is_none_or(|end| e.timestamp <= end)
+    });
+    if let (true, Some(start)) = (rebase, start) {
+        for event in events {
+            event.timestamp = (event.timestamp - start).max(0.0);
+        }
+    }
+}
+
+/// Ends every section cleanly: just before each boundary (e.g. marker times) and after the
+/// last event, adds a NoteOff for every note still held. NoteOffs the file sends later for
+/// notes closed this way are dropped. Expects events and boundaries sorted by timestamp.
+pub fn off_at_section_ends(events: &mut Vec<MidiEvent>, boundaries: &[f64]) {
+    // (channel, note, track) of every NoteOn not yet turned off, oldest first
+    let mut held: Vec<(u8, u8, usize)> = Vec::new();
+    let mut closed: HashSet<(u8, u8)> = HashSet::new();
+    let mut boundaries = boundaries.iter().copied().peekable();
+    let last_timestamp = events.last().map_or(0.0, |e| e.timestamp);
+    let mut result = Vec::with_capacity(events.len());
+
+    for event in events.drain(..) {
+        while let Some(boundary) = boundaries.next_if(|b| *b <= event.timestamp) {
+            close_held_notes(&mut result, &mut held, &mut closed, boundary);
+        }
+        match event.message {
+            Message::NoteOn(note, _) => {
+                closed.remove(&(event.channel, note));
+                held.push((event.channel, note, event.track));
+            }
+            Message::NoteOff(note, _) => {
+                match held
+                    .iter()
+                    .position(|(c, n, _)| (*c, *n) == (event.channel, note))
+                {
+                    Some(i) => {
+                        held.remove(i);
+                    }
+                    None if closed.contains(&(event.channel, note)) => continue,
+                    None => {}
+                }
+            }
+            _ => {}
+        }
+        result.push(event);
+    }
+    let end = boundaries.next().unwrap_or(last_timestamp);
+    close_held_notes(&mut result, &mut held, &mut closed, end);
+    *events = result;
+}
+
+fn close_held_notes(
+    events: &mut Vec<MidiEvent>,
+    held: &mut Vec<(u8, u8, usize)>,
+    closed: &mut HashSet<(u8, u8)>,
+    timestamp: f64,
+) {
+    for (channel, note, track) in held.drain(..) {
+        closed.insert((channel, note));
+        events.push(MidiEvent {
+            timestamp,
+            message: Message::NoteOff(note, 0),
+            channel,
+            track,
+            bar_beat: None,
+        });
+    }
+}
+
+/// Starts every section with the controller state it inherits: at each boundary (e.g.
+/// marker times), re-sends the latest value of every controller set before it, per channel.
+/// A controller that gets a new value right at the boundary isn't carried, so its stale
+/// value can't be sent just before the new one. Expects events and boundaries sorted by
+/// timestamp, and 14-bit pairs already merged (see `merge_cc14`).
+pub fn carry_cc(events: &mut Vec<MidiEvent>, boundaries: &[f64]) {
+    // per boundary, the controllers an event sets exactly there
+    let set_at_boundary: Vec<HashSet<(u8, u8, bool)>> = boundaries
+        .iter()
+        .map(|&boundary| {
+            let start = events.partition_point(|e| e.timestamp < boundary);
+            events[start..]
+                .iter()
+                .take_while(|e| e.timestamp == boundary)
+                .filter_map(|e| cc_state(e).map(|(key, _)| key))
+                .collect()
+        })
+        .collect();
+    // (channel, controller, 14-bit) -> (latest value, source track)
+    let mut state: BTreeMap<(u8, u8, bool), (u16, usize)> = BTreeMap::new();
+    let mut boundaries = boundaries.iter().copied().zip(&set_at_boundary).peekable();
+    let mut result = Vec::with_capacity(events.len());
+
+    for event in events.drain(..) {
+        while let Some((boundary, set)) = boundaries.next_if(|(b, _)| *b <= event.timestamp) {
+            push_cc_state(&mut result, &state, set, boundary);
+        }
+        if let Some((key, value)) = cc_state(&event) {
+            state.insert(key, (value, event.track));
+        }
+        result.push(event);
+    }
+    for (boundary, set) in boundaries {
+        push_cc_state(&mut result, &state, set, boundary);
+    }
+    *events = result;
+}
+
+// ((channel, controller, 14-bit), value) of a controller change
+fn cc_state(event: &MidiEvent) -> Option<((u8, u8, bool), u16)> {
+    match event.message {
+        Message::ControlChange(controller, value) => {
+            Some(((event.channel, controller, false), value as u16))
+        }
+        Message::ControlChange14(controller, value) => {
+            Some(((event.channel, controller, true), value))
+        }
+        _ => None,
+    }
+}
+
+fn push_cc_state(
+    events: &mut Vec<MidiEvent>,
+    state: &BTreeMap<(u8, u8, bool), (u16, usize)>,
+    skip: &HashSet<(u8, u8, bool)>,
+    timestamp: f64,
+) {
+    for (&(channel, controller, wide), &(value, track)) in state {
+        if skip.contains(&(channel, controller, wide)) {
+            continue;
+        }
+        let message = if wide {
+            Message::ControlChange14(controller, value)
+        } else {
+            Message::ControlChange(controller, value as u8)
+        };
+        events.push(MidiEvent {
+            timestamp,
+            message,
+            channel,
+            track,
+            bar_beat: None,
+        });
+    }
+}
+
+/// Replaces each NoteOn/NoteOff pair (same channel and note) with a single `Message::Note`
+/// carrying the duration. Overlapping NoteOns of the same pitch are closed first in, first out.
+/// NoteOns that are never turned off last until the final event; stray NoteOffs are dropped.
+/// Expects events sorted by timestamp.
+pub fn notes_as_duration(events: &mut Vec<MidiEvent>) {
+    let last_timestamp = events.last().map_or(0.0, |e| e.timestamp);
+    // (channel, note) -> indices of NoteOns still waiting for their NoteOff
+    let mut open: HashMap<(u8, u8), VecDeque<usize>> = HashMap::new();
+    let mut keep = vec![true; events.len()];
+
+    for i in 0..events.len() {
+        let (channel, timestamp) = (events[i].channel, events[i].timestamp);
+        match events[i].message {
+            Message::NoteOn(note, _) => open.entry((channel, note)).or_default().push_back(i),
+            Message::NoteOff(note, _) => {
+                keep[i] = false;
+                if let Some(on) = open.get_mut(&(channel, note)).and_then(|q| q.pop_front()) {
+                    close_note(&mut events[on], timestamp);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    for ((channel, note), ons) in open {
+        for on in ons {
+            warn!(
+                "note {} on channel {} at {:.3}s is never turned off, ending it at {:.3}s",
+                note, channel, events[on].timestamp, last_timestamp
+            );
+            close_note(&mut events[on], last_timestamp);
+        }
+    }
+
+    let mut keep = keep.into_iter();
+    events.retain(|_| keep.next().unwrap_or(true));
+}
+
+fn close_note(event: &mut MidiEvent, off_timestamp: f64) {
+    if let Message::NoteOn(note, velocity) = event.message {
+        event.message = Message::Note(note, velocity, off_timestamp - event.timestamp);
+    }
+}
+
+/// Drops ControlChanges that repeat the previous value of the same controller on the same
+/// channel. The first value and every change are kept. Expects events sorted by timestamp.
+pub fn dedupe_cc(events: &mut Vec<MidiEvent>) {
+    // (channel, controller) -> last emitted value
+    let mut last: HashMap<(u8, u8), u8> = HashMap::new();
+    events.retain(|e| match e.message {
+        Message::ControlChange(controller, value) => {
+            last.insert((e.channel, controller), value) != Some(value)
+        }
+        _ => true,
+    });
+}
+
+/// Drops an event when the event right before it has the same channel and message at the
+/// same time (within `epsilon_ms`), e.g. controller data doubled across merged tracks.
+/// Unlike `dedupe_cc`, only exact neighbours count.
+pub fn dedupe_exact(events: &mut Vec<MidiEvent>, epsilon_ms: f64) {
+    events.dedup_by(|e, prev| {
+        same_time(e.timestamp, prev.timestamp, epsilon_ms)
+            && e.channel == prev.channel
+            && e.message == prev.message
+    });
+}
+
+/// Thins dense CC automation: per channel and controller, drops CCs that come less than
+/// `min_gap_ms` after the last kept one. The last value of each run is always kept, so
+/// the controller still ends up where the automation left it. Expects events sorted by timestamp.
+pub fn thin_cc(events: &mut Vec<MidiEvent>, min_gap_ms: f64) {
+    let min_gap = min_gap_ms / 1000.0;
+    // (channel, controller) -> (timestamp of the last kept CC, most recent dropped CC)
+    let mut state: HashMap<(u8, u8), (f64, Option<usize>)> = HashMap::new();
+    let mut keep = vec![true; events.len()];
+
+    for i in 0..events.len() {
+        let controller = match events[i].message {
+            Message::ControlChange(controller, _) | Message::ControlChange14(controller, _) => {
+                controller
+            }
+            _ => continue,
+        };
+        let timestamp = events[i].timestamp;
+        let Some((last_kept, dropped)) = state.get_mut(&(events[i].channel, controller)) else {
+            state.insert((events[i].channel, controller), (timestamp, None));
+            continue;
+        };
+        // a dropped CC followed by a pause ended its run, so it carries the final value
+        if let Some(d) = dropped.filter(|d| timestamp - events[*d].timestamp >= min_gap) {
+            keep[d] = true;
+            *last_kept = events[d].timestamp;
+            *dropped = None;
+        }
+        if timestamp - *last_kept >= min_gap {
+            *last_kept = timestamp;
+            *dropped = None;
+        } else {
+            keep[i] = false;
+            *dropped = Some(i);
+        }
+    }
+    for (_, dropped) in state.into_values() {
+        if let Some(d) = dropped {
+            keep[d] = true;
+        }
+    }
+
+    let mut keep = keep.into_iter();
+    events.retain(|_| keep.next().unwrap_or(true));
+}
+
+/// Merges a `coarse` CC immediately followed (among that channel's CCs) by a `fine` CC into
+/// one 14-bit `Message::ControlChange14` at the coarse event's time. A coarse byte with no
+/// fine partner, or a lone fine byte, passes through unchanged.
+pub fn merge_cc14(events: &mut Vec<MidiEvent>, coarse: u8, fine: u8) {
+    // channel -> index of a coarse CC still waiting for its fine byte
+    let mut pending: HashMap<u8, usize> = HashMap::new();
+    let mut keep = vec![true; events.len()];
+
+    for i in 0..events.len() {
+        let channel = events[i].channel;
+        let Message::ControlChange(controller, value) = events[i].message else {
+            continue;
+        };
+        let waiting = pending.remove(&channel);
+        if controller == coarse {
+            pending.insert(channel, i);
+        } else if let (true, Some(coarse_index)) = (controller == fine, waiting) {
+            if let Message::ControlChange(_, msb) = events[coarse_index].message {
+                events[coarse_index].message =
+                    Message::ControlChange14(coarse, (msb as u16) << 7 | value as u16);
+                keep[i] = false;
+            }
+        }
+    }
+
+    let mut keep = keep.into_iter();
+    events.retain(|_| keep.next().unwrap_or(true));
+}
+
+/// Treats `channel` as a set of mutually exclusive scenes: each NoteOn there is preceded by
+/// a NoteOff (at the same timestamp) for the scene note still held on that channel, even if
+/// the file never turned it off. Expects events sorted by timestamp.
+pub fn exclusive_scenes(events: &mut Vec<MidiEvent>, channel: u8) {
+    let mut active: Option<u8> = None;
+    let mut result = Vec::with_capacity(events.len());
+    for event in events.drain(..) {
+        if event.channel == channel {
+            match event.message {
+                Message::NoteOn(note, _) => {
+                    if let Some(previous) = active.replace(note) {
+                        result.push(MidiEvent {
+                            message: Message::NoteOff(previous, 0),
+                            ..event
+                        });
+                    }
+                }
+                Message::NoteOff(note, _) if active == Some(note) => active = None,
+                _ => {}
+            }
+        }
+        result.push(event);
+    }
+    *events = result;
+}
+
+/// Moves every non-note event that happens before the first NoteOn to the front of
+/// the list at time 0, ordered bank select, then program change, then other control changes.
+/// Returns the number of events in the setup block.
+pub fn hoist_setup_block(events: &mut Vec<MidiEvent>) -> usize {
+    let first_note_on = events
+        .iter()
+        .filter(|e| matches!(e.message, Message::NoteOn(..) | Message::Note(..)))
+        .map(|e| e.timestamp)
+        .fold(f64::INFINITY, f64::min);
+
+    let (mut setup, rest): (Vec<MidiEvent>, Vec<MidiEvent>) = events.drain(..).partition(|e| {
+        e.timestamp < first_note_on
+            && !matches!(
+                e.message,
+                Message::NoteOn(..) | Message::NoteOff(..) | Message::Note(..)
+            )
+    });
+
+    setup.sort_by_key(setup_priority);
+    for event in &mut setup {
+        event.timestamp = 0.0;
+    }
+
+    let setup_len = setup.len();
+    events.extend(setup);
+    events.extend(rest);
+    setup_len
+}
+
+fn setup_priority(event: &MidiEvent) -> u8 {
+    match event.message {
+        // bank select MSB/LSB must land before anything it affects
+        Message::ControlChange(0, _) | Message::ControlChange(32, _) => 0,
+        Message::ProgramChange(_) => 1,
+        _ => 2,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_time_includes_the_epsilon_boundary() {
+        assert!(same_time(0.5, 0.75, 250.0));
+        assert!(same_time(0.75, 0.5, 250.0));
+        assert!(!same_time(0.5, 0.75, 249.0));
+        assert!(same_time(1.0, 1.0, 0.0));
+    }
+
+    fn event(timestamp: f64, message: Message) -> MidiEvent {
+        MidiEvent {
+            timestamp,
+            message,
+            channel: 1,
+            track: 0,
+            bar_beat: None,
+        }
+    }
+
+    #[test]
+    fn offset_shifts_events_and_clamps_at_zero() {
+        let mut events = vec![
+            event(0.1, Message::NoteOn(60, 100)),
+            event(1.0, Message::NoteOff(60, 0)),
+        ];
+        apply_offset(&mut events, -250);
+        assert_eq!(events[0].timestamp, 0.0);
+        assert_eq!(events[1].timestamp, 0.75);
+        apply_offset(&mut events, 1500);
+        assert_eq!(events[0].timestamp, 1.5);
+        assert_eq!(events[1].timestamp, 2.25);
+    }
+
+    fn summary(events: &[MidiEvent]) -> Vec<(f64, &Message)> {
+        events.iter().map(|e| (e.timestamp, &e.message)).collect()
+    }
+
+    #[test]
+    fn note_off_collisions_use_the_epsilon() {
+        let events = || {
+            vec![
+                event(1.0, Message::NoteOff(60, 0)),
+                event(1.001, Message::NoteOn(62, 100)),
+            ]
+        };
+        let mut exact = events();
+        skip_off_note_collisions(&mut exact, 0.0);
+        assert_eq!(exact.len(), 2);
+
+        let mut within = events();
+        skip_off_note_collisions(&mut within, 1.0);
+        assert_eq!(summary(&within), [(1.001, &Message::NoteOn(62, 100))]);
+    }
+
+    #[test]
+    fn note_off_collisions_drop_every_off_alongside_an_on() {
+        // two offs and an on at the same time: both offs go
+        let mut events = vec![
+            event(1.0, Message::NoteOff(60, 0)),
+            event(1.0, Message::NoteOff(61, 0)),
+            event(1.0, Message::NoteOn(62, 100)),
+        ];
+        skip_off_note_collisions(&mut events, 0.0);
+        assert_eq!(summary(&events), [(1.0, &Message::NoteOn(62, 100))]);
+    }
+
+    #[test]
+    fn note_off_collisions_keep_offs_at_other_times() {
+        let mut events = vec![
+            event(0.5, Message::NoteOff(59, 0)),
+            event(1.0, Message::NoteOff(60, 0)),
+            event(1.0, Message::NoteOn(62, 100)),
+            event(1.5, Message::NoteOff(62, 0)),
+        ];
+        skip_off_note_collisions(&mut events, 0.0);
+        assert_eq!(
+            summary(&events),
+            [
+                (0.5, &Message::NoteOff(59, 0)),
+                (1.0, &Message::NoteOn(62, 100)),
+                (1.5, &Message::NoteOff(62, 0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn notes_as_duration_pairs_ons_with_offs() {
+        let mut events = vec![
+            event(0.0, Message::NoteOn(60, 100)),
+            event(0.5, Message::NoteOn(60, 90)),
+            event(1.0, Message::NoteOff(60, 0)),
+            event(1.0, Message::ControlChange(7, 64)),
+            event(1.5, Message::NoteOff(60, 0)),
+            // stray off with no on
+            event(2.0, Message::NoteOff(61, 0)),
+        ];
+        notes_as_duration(&mut events);
+        // overlapping ons of one pitch close first in, first out
+        assert_eq!(
+            summary(&events),
+            [
+                (0.0, &Message::Note(60, 100, 1.0)),
+                (0.5, &Message::Note(60, 90, 1.0)),
+                (1.0, &Message::ControlChange(7, 64)),
+            ]
+        );
+    }
+
+    #[test]
+    fn notes_as_duration_ends_unclosed_notes_at_the_last_event() {
+        let mut events = vec![
+            event(0.5, Message::NoteOn(60, 100)),
+            event(2.0, Message::ControlChange(7, 64)),
+        ];
+        notes_as_duration(&mut events);
+        assert_eq!(events[0].message, Message::Note(60, 100, 1.5));
+    }
+
+    #[test]
+    fn dedupe_cc_drops_repeated_values_per_controller() {
+        let mut events = vec![
+            event(0.0, Message::ControlChange(7, 64)),
+            event(0.1, Message::ControlChange(10, 64)),
+            event(0.2, Message::ControlChange(7, 64)),
+            event(0.3, Message::ControlChange(7, 65)),
+            event(0.4, Message::ControlChange(7, 64)),
+        ];
+        let mut other_channel = event(0.5, Message::ControlChange(7, 64));
+        other_channel.channel = 2;
+        events.push(other_channel);
+        dedupe_cc(&mut events);
+        let times: Vec<f64> = events.iter().map(|e| e.timestamp).collect();
+        assert_eq!(times, [0.0, 0.1, 0.3, 0.4, 0.5]);
+    }
+
+    #[test]
+    fn merge_cc14_combines_coarse_and_fine() {
+        let mut events = vec![
+            event(0.0, Message::ControlChange(7, 0x40)),
+            event(0.0, Message::ControlChange(39, 0x01)),
+            // a coarse byte with no fine partner passes through
+            event(1.0, Message::ControlChange(7, 0x20)),
+            event(1.0, Message::ControlChange(10, 64)),
+            // and so does a lone fine byte
+            event(2.0, Message::ControlChange(39, 0x02)),
+        ];
+        merge_cc14(&mut events, 7, 39);
+        assert_eq!(
+            summary(&events),
+            [
+                (0.0, &Message::ControlChange14(7, 0x2001)),
+                (1.0, &Message::ControlChange(7, 0x20)),
+                (1.0, &Message::ControlChange(10, 64)),
+                (2.0, &Message::ControlChange(39, 0x02)),
+            ]
+        );
+    }
+
+    #[test]
+    fn thin_cc_thins_a_dense_ramp_but_keeps_its_end() {
+        // 500 CCs 1/512s apart, thinned to one per 8/512s
+        let mut events: Vec<MidiEvent> = (0..500)
+            .map(|i| {
+                event(
+                    i as f64 / 512.0,
+                    Message::ControlChange(1, (i * 127 / 499) as u8),
+                )
+            })
+            .collect();
+        thin_cc(&mut events, 8.0 / 512.0 * 1000.0);
+
+        // every 8th CC (0..=496), plus the final value
+        assert_eq!(events.len(), 64);
+        assert_eq!(events[0].message, Message::ControlChange(1, 0));
+        assert_eq!(events[63].message, Message::ControlChange(1, 127));
+        assert_eq!(events[63].timestamp, 499.0 / 512.0);
+        assert!(events[..63]
+            .windows(2)
+            .all(|pair| pair[1].timestamp - pair[0].timestamp >= 8.0 / 512.0));
+    }
+
+    #[test]
+    fn thin_cc_keeps_the_last_value_before_a_pause() {
+        let mut events = vec![
+            event(0.0, Message::ControlChange(1, 0)),
+            event(0.005, Message::ControlChange(1, 10)),
+            event(0.010, Message::ControlChange(1, 20)),
+            event(1.0, Message::ControlChange(1, 30)),
+        ];
+        thin_cc(&mut events, 50.0);
+        let values: Vec<&Message> = events.iter().map(|e| &e.message).collect();
+        assert_eq!(
+            values,
+            [
+                &Message::ControlChange(1, 0),
+                &Message::ControlChange(1, 20),
+                &Message::ControlChange(1, 30)
+            ]
+        );
+    }
+
+    #[test]
+    fn dedupe_exact_drops_identical_neighbours_only() {
+        let mut events = vec![
+            event(1.0, Message::ControlChange(7, 100)),
+            event(1.0, Message::ControlChange(7, 100)),
+            event(1.0, Message::ControlChange(7, 90)),
+            event(2.0, Message::ControlChange(7, 90)),
+        ];
+        dedupe_exact(&mut events, 0.0);
+        assert_eq!(
+            summary(&events),
+            [
+                (1.0, &Message::ControlChange(7, 100)),
+                (1.0, &Message::ControlChange(7, 90)),
+                (2.0, &Message::ControlChange(7, 90)),
+            ]
+        );
+    }
+
+    #[test]
+    fn exclusive_scenes_turn_off_the_previous_scene() {
+        let mut other_channel = event(1.0, Message::NoteOn(40, 100));
+        other_channel.channel = 2;
+        let mut events = vec![
+            event(0.0, Message::NoteOn(36, 100)),
+            other_channel,
+            event(1.0, Message::NoteOn(37, 100)),
+            event(2.0, Message::NoteOff(37, 0)),
+            event(3.0, Message::NoteOn(38, 100)),
+        ];
+        exclusive_scenes(&mut events, 1);
+        assert_eq!(
+            summary(&events),
+            [
+                (0.0, &Message::NoteOn(36, 100)),
+                (1.0, &Message::NoteOn(40, 100)),
+                (1.0, &Message::NoteOff(36, 0)),
+                (1.0, &Message::NoteOn(37, 100)),
+                (2.0, &Message::NoteOff(37, 0)),
+                // 37 was already off, so nothing to close
+                (3.0, &Message::NoteOn(38, 100)),
+            ]
+        );
+    }
+
+    #[test]
+    fn delta_times_measure_from_the_previous_event() {
+        let mut events = vec![
+            event(1.0, Message::NoteOn(60, 100)),
+            event(1.5, Message::NoteOff(60, 0)),
+            event(1.5, Message::NoteOn(62, 100)),
+            event(3.0, Message::NoteOff(62, 0)),
+        ];
+        to_delta_times(&mut events, false);
+        let times: Vec<f64> = events.iter().map(|e| e.timestamp).collect();
+        assert_eq!(times, [1.0, 0.5, 0.0, 1.5]);
+    }
+
+    #[test]
+    fn delta_times_can_start_the_first_event_at_zero() {
+        let mut events = vec![
+            event(1.0, Message::NoteOn(60, 100)),
+            event(1.5, Message::NoteOff(60, 0)),
+        ];
+        to_delta_times(&mut events, true);
+        let times: Vec<f64> = events.iter().map(|e| e.timestamp).collect();
+        assert_eq!(times, [0.0, 0.5]);
+    }
+
+    #[test]
+    fn off_at_section_ends_closes_held_notes_at_each_boundary() {
+        let mut events = vec![
+            event(0.0, Message::NoteOn(60, 100)),
+            event(1.0, Message::NoteOn(62, 100)),
+            event(2.0, Message::NoteOn(64, 100)),
+            // the file's own off for 60 comes after the section ended
+            event(2.5, Message::NoteOff(60, 0)),
+        ];
+        off_at_section_ends(&mut events, &[2.0]);
+        assert_eq!(
+            summary(&events),
+            [
+                (0.0, &Message::NoteOn(60, 100)),
+                (1.0, &Message::NoteOn(62, 100)),
+                (2.0, &Message::NoteOff(60, 0)),
+                (2.0, &Message::NoteOff(62, 0)),
+                (2.0, &Message::NoteOn(64, 100)),
+                (2.5, &Message::NoteOff(64, 0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn off_at_section_ends_leaves_finished_notes_alone() {
+        let mut events = vec![
+            event(0.0, Message::NoteOn(60, 100)),
+            event(0.5, Message::NoteOff(60, 0)),
+            event(2.0, Message::NoteOn(60, 100)),
+            event(2.5, Message::NoteOff(60, 0)),
+        ];
+        off_at_section_ends(&mut events, &[1.0]);
+        assert_eq!(events.len(), 4);
+    }
+
+    #[test]
+    fn carry_cc_resends_controller_state_at_a_section_start() {
+        let mut events = vec![
+            event(0.0, Message::ControlChange(7, 90)),
+            event(1.0, Message::ControlChange(7, 40)),
+            event(1.5, Message::ControlChange(11, 127)),
+            event(2.0, Message::NoteOn(60, 100)),
+        ];
+        carry_cc(&mut events, &[2.0]);
+        assert_eq!(
+            summary(&events),
+            [
+                (0.0, &Message::ControlChange(7, 90)),
+                (1.0, &Message::ControlChange(7, 40)),
+                (1.5, &Message::ControlChange(11, 127)),
+                (2.0, &Message::ControlChange(7, 40)),
+                (2.0, &Message::ControlChange(11, 127)),
+                (2.0, &Message::NoteOn(60, 100)),
+            ]
+        );
+    }
+
+    #[test]
+    fn carry_cc_has_nothing_to_carry_into_the_first_section() {
+        let mut events = vec![event(0.0, Message::ControlChange(7, 90))];
+        carry_cc(&mut events, &[0.0]);
+        assert_eq!(events.len(), 1);
+    }
+
+    #[test]
+    fn carry_cc_skips_a_controller_set_on_the_boundary() {
+        let mut events = vec![
+            event(0.0, Message::ControlChange(7, 90)),
+            event(0.0, Message::ControlChange(11, 127)),
+            event(2.0, Message::ControlChange(7, 40)),
+        ];
+        carry_cc(&mut events, &[2.0]);
+        assert_eq!(
+            summary(&events),
+            [
+                (0.0, &Message::ControlChange(7, 90)),
+                (0.0, &Message::ControlChange(11, 127)),
+                (2.0, &Message::ControlChange(11, 127)),
+                (2.0, &Message::ControlChange(7, 40)),
+            ]
+        );
+    }
+
+    #[test]
+    fn carry_cc_carries_merged_14_bit_controllers() {
+        let mut events = vec![
+            event(0.0, Message::ControlChange(7, 0x40)),
+            event(0.0, Message::ControlChange(39, 0x01)),
+            event(2.0, Message::NoteOn(60, 100)),
+        ];
+        merge_cc14(&mut events, 7, 39);
+        carry_cc(&mut events, &[2.0]);
+        assert_eq!(
+            summary(&events),
+            [
+                (0.0, &Message::ControlChange14(7, 0x2001)),
+                (2.0, &Message::ControlChange14(7, 0x2001)),
+                (2.0, &Message::NoteOn(60, 100)),
+            ]
+        );
+    }
+
+    #[test]
+    fn dedupe_exact_drops_repeats_within_the_epsilon() {
+        let mut events = vec![
+            event(1.0, Message::ControlChange(7, 100)),
+            event(1.0005, Message::ControlChange(7, 100)),
+            event(1.0005, Message::ControlChange(7, 90)),
+            event(2.0, Message::ControlChange(7, 90)),
+        ];
+        dedupe_exact(&mut events, 1.0);
+        assert_eq!(
+            summary(&events),
+            [
+                (1.0, &Message::ControlChange(7, 100)),
+                (1.0005, &Message::ControlChange(7, 90)),
+                (2.0, &Message::ControlChange(7, 90)),
+            ]
+        );
+    }
+
+    #[test]
+    fn dedupe_exact_keeps_near_repeats_without_an_epsilon() {
+        let mut events = vec![
+            event(1.0, Message::ControlChange(7, 100)),
+            event(1.0005, Message::ControlChange(7, 100)),
+        ];
+        dedupe_exact(&mut events, 0.0);
+        assert_eq!(events.len(), 2);
+    }
+}